@@ -0,0 +1,231 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+use crate::{db_layer, fixed_point_util::Rounding, transaction_processing};
+
+/// Reads transactions from a CSV or JSON-lines file and outputs the final state of every client as
+/// CSV or JSON
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The CSV or JSON-lines files to read transactions from, each one processed as its own batch.
+    /// A directory stands for every file in it and a glob pattern, quoted against the shell, for
+    /// every file matching it. Transactions are read from stdin if not given
+    #[arg(conflicts_with = "listen")]
+    pub input: Vec<PathBuf>,
+
+    /// The order the files of a directory or pattern are read in: `name`, or `timestamp` for the
+    /// digits in their names such as `20241018` in `tx-2024-10-18.csv`
+    #[arg(long, value_enum, default_value_t = InputOrder::Name)]
+    pub order: InputOrder,
+
+    /// The format of every input. Defaults to JSON-lines for files ending in `.json` or `.jsonl`
+    /// and to CSV otherwise
+    #[arg(long, value_enum, conflicts_with = "listen")]
+    pub input_format: Option<Format>,
+
+    /// Accept transactions from any number of TCP connections on the given address instead of
+    /// reading a file or stdin, each connection sending CSV records with or without a header line.
+    /// Connections are accepted until SIGINT or SIGTERM is received
+    #[arg(long)]
+    pub listen: Option<String>,
+
+    /// Where client and transaction data is held
+    #[arg(long, value_enum, default_value_t = Backend::Memory)]
+    pub backend: Backend,
+
+    /// The path of the sled database used by the `sled` backend
+    #[arg(long, default_value = "./database", global = true)]
+    pub db_path: PathBuf,
+
+    /// The number of workers processing transactions in parallel. The transactions of a client are
+    /// always processed in order by the same worker
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub workers: u16,
+
+    /// The number of transactions to allow in the queue between the reader and the processor. Each
+    /// transaction will be roughly 120 bytes (plus padding) and the overhead of the channel
+    #[arg(long, default_value_t = 1024)]
+    pub reader_buffer: usize,
+
+    /// The number of clients to allow in the queue between the database and the writer. Each
+    /// client will be roughly 200 bytes (plus padding) and the overhead of the channel
+    #[arg(long, default_value_t = 1024)]
+    pub db_buffer: usize,
+
+    /// The file to write the final state of every client to. Clients are written to stdout if not
+    /// given
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+
+    /// The format clients are written in. Defaults to a JSON array if the output ends in `.json`,
+    /// to JSON-lines if it ends in `.jsonl`, and to CSV otherwise
+    #[arg(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+
+    /// The order clients are written in: `id` for ascending client IDs or `balance` for descending
+    /// total funds
+    #[arg(long, default_value = "id")]
+    pub sort: db_layer::ClientOrder,
+
+    /// The file to report every refused transaction to, as JSON-lines if it ends in `.json` or
+    /// `.jsonl` or as CSV otherwise
+    #[arg(long)]
+    pub rejections: Option<PathBuf>,
+
+    /// Stop on the first malformed record instead of skipping it
+    #[arg(long)]
+    pub strict: bool,
+
+    /// How amounts with more than four digits behind the decimal point are handled: `reject`,
+    /// `half-even`, or `truncate`
+    #[arg(long, default_value = "reject")]
+    pub rounding: Rounding,
+
+    /// Refuse disputes, resolves, and chargebacks for clients whose account is locked. Like every
+    /// policy flag, it can't be changed for a sled database once the database has been processed
+    #[arg(long)]
+    pub reject_disputes_on_locked: bool,
+
+    /// Allow a transaction whose dispute was resolved to be disputed again. Like every policy flag,
+    /// it can't be changed for a sled database once the database has been processed
+    #[arg(long)]
+    pub redispute_resolved: bool,
+
+    /// How disputes of withdrawals are handled: `reverse` or `reject`. Like every policy flag, it
+    /// can't be changed for a sled database once the database has been processed
+    #[arg(long, default_value = "reverse")]
+    pub withdrawal_disputes: transaction_processing::WithdrawalDisputes,
+}
+
+/// Commands run instead of processing transactions
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write the audit log of a client in the sled database as JSON-lines, every entry holding a
+    /// transaction, whether it was applied or refused, and the client's balances before and after
+    History {
+        /// The ID of the client
+        #[arg(long)]
+        client: u16,
+
+        /// Only include entries from this time on, in milliseconds since the Unix epoch
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+
+        /// Only include entries before this time, in milliseconds since the Unix epoch
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+    },
+
+    /// Write a client in the sled database as CSV as it was at the end of a batch, every input
+    /// processed with the `sled` backend being a batch numbered from 0 on
+    Balance {
+        /// The ID of the client
+        #[arg(long)]
+        client: u16,
+
+        /// The number of the batch
+        #[arg(long)]
+        batch: u64,
+    },
+
+    /// Process every CSV or JSON-lines file dropped into the inbox directory against the sled
+    /// database until SIGINT or SIGTERM is received, with the options given ahead of the command.
+    /// Each file is a batch, moved into the `processed` or the `failed` subdirectory of the inbox
+    /// once done, after which the final state of every client is written
+    Watch {
+        /// The directory to watch
+        inbox: PathBuf,
+
+        /// How often to look for new files, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+    },
+
+    /// Rebuild every client in the sled database from nothing but its log of processed
+    /// transactions, with the policy stored by the first run against the database, and write them
+    /// as CSV
+    Replay {
+        /// Instead of writing the rebuilt clients, write every client whose stored state differs
+        /// from the rebuilt one as JSON-lines and exit with code 1 if there is any
+        #[arg(long)]
+        verify: bool,
+    },
+}
+
+/// The implementors of [`crate::db_layer::DbLayer`] which may be selected at runtime
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Backend {
+    /// Hold everything in memory, losing it when the run ends
+    Memory,
+    /// Persist everything to a sled database between runs
+    Sled,
+}
+
+/// The formats transactions may be read in
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Format {
+    /// Comma separated values with an optional header line
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+/// The orders the files of a directory or glob pattern may be read in
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum InputOrder {
+    /// By file name
+    Name,
+    /// By the digits in the file name taken together, then by file name
+    Timestamp,
+}
+
+/// The formats clients may be written in
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OutputFormat {
+    /// Comma separated values with a header line
+    Csv,
+    /// A single JSON array of client objects
+    Json,
+    /// One JSON client object per line
+    JsonLines,
+}
+
+impl Cli {
+    /// The format of the input file at the given path, either as given or judging by its extension
+    pub fn input_format(&self, path: &Path) -> Format {
+        match self.input_format {
+            Some(format) => format,
+            None if crate::is_json(path) => Format::Json,
+            None => Format::Csv,
+        }
+    }
+
+    /// The format clients are written in, either as given or judging by the output's extension
+    pub fn output_format(&self) -> OutputFormat {
+        let extension = self
+            .output
+            .as_deref()
+            .and_then(Path::extension)
+            .and_then(|extension| extension.to_str());
+
+        match (self.output_format, extension) {
+            (Some(format), _) => format,
+            (None, Some("json")) => OutputFormat::Json,
+            (None, Some("jsonl")) => OutputFormat::JsonLines,
+            (None, _) => OutputFormat::Csv,
+        }
+    }
+
+    /// The rules given to [`transaction_processing::process_transaction`]
+    pub fn policy(&self) -> transaction_processing::Policy {
+        transaction_processing::Policy {
+            disputes_on_locked: !self.reject_disputes_on_locked,
+            redispute_resolved: self.redispute_resolved,
+            withdrawal_disputes: self.withdrawal_disputes,
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range};
 
 use super::*;
 
@@ -7,6 +7,20 @@ pub struct HashMapDb {
     transactions_map: HashMap<u32, Transaction>,
     clients_map: HashMap<u16, Client>,
 
+    /// The state of every client at the end of each batch in which it changed, oldest first, and
+    /// the last batch recorded
+    snapshots_map: HashMap<u16, Vec<(u64, Client)>>,
+    last_batch: Option<u64>,
+
+    /// The cursor of every client for every source it was read from
+    cursors_map: HashMap<(String, u16), Cursor>,
+
+    /// The highest sequence number of any cursor
+    last_sequence: Option<u64>,
+
+    /// The resume point of every source which can be read from an offset
+    resume_points: HashMap<String, ResumePoint>,
+
     clients_sender: mpsc::Sender<Result<Client, Error>>,
     clients_receiver: Option<mpsc::Receiver<Result<Client, Error>>>,
 }
@@ -21,40 +35,117 @@ impl HashMapDb {
         HashMapDb {
             transactions_map,
             clients_map,
+            snapshots_map: HashMap::new(),
+            last_batch: None,
+            cursors_map: HashMap::new(),
+            last_sequence: None,
+            resume_points: HashMap::new(),
             clients_sender,
             clients_receiver,
         }
     }
 
-    async fn stream(mut self) {
-        for (_id, client) in self.clients_map.drain() {
-            self.clients_sender.send(Ok(client)).await.unwrap();
+    async fn stream(mut self, order: ClientOrder) {
+        let mut clients = self
+            .clients_map
+            .drain()
+            .map(|(_id, client)| client)
+            .collect::<Vec<_>>();
+        clients.sort_unstable_by(|a, b| order.compare(a, b));
+
+        for client in clients {
+            // Stop if the receiver has been closed as nothing is listening anymore
+            if self.clients_sender.send(Ok(client)).await.is_err() {
+                break;
+            }
         }
     }
 }
 
 #[async_trait]
 impl DbLayer for HashMapDb {
-    async fn write_transaction(&mut self, transaction: Transaction) -> Result<(), Error> {
-        self.transactions_map.insert(transaction.tx, transaction);
+    async fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        for transaction in batch.transactions {
+            self.transactions_map.insert(transaction.tx, transaction);
+        }
+        for client in batch.clients {
+            self.clients_map.insert(client.client, client);
+        }
+        // No audit log is kept as the clients are lost at the end of the run, before the log could
+        // ever be read with the `history` command
+        if let Some((client_id, cursor)) = batch.cursor {
+            self.last_sequence = self.last_sequence.max(Some(cursor.sequence));
+            self.cursors_map
+                .insert((cursor.source.clone(), client_id), cursor);
+        }
         Ok(())
     }
+
     async fn get_transaction(&mut self, transaction_id: u32) -> Result<Option<Transaction>, Error> {
         Ok(self.transactions_map.get(&transaction_id).copied())
     }
 
-    async fn write_client(&mut self, client: Client) -> Result<(), Error> {
-        self.clients_map.insert(client.client, client);
-        Ok(())
-    }
     async fn get_client(&mut self, client_id: u16) -> Result<Option<Client>, Error> {
         Ok(self.clients_map.get(&client_id).copied())
     }
 
-    async fn stream_clients(mut self) -> mpsc::Receiver<Result<Client, Error>> {
+    async fn client_history(
+        &mut self,
+        _client_id: u16,
+        _timestamps: Range<u64>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn snapshot(&mut self, batch: u64) -> Result<(), Error> {
+        for (id, client) in self.clients_map.iter() {
+            let snapshots = self.snapshots_map.entry(*id).or_default();
+            if snapshots.last().map(|(_, last)| last) != Some(client) {
+                snapshots.push((batch, *client));
+            }
+        }
+        self.last_batch = Some(batch);
+        Ok(())
+    }
+
+    async fn last_batch(&mut self) -> Result<Option<u64>, Error> {
+        Ok(self.last_batch)
+    }
+
+    async fn client_as_of(&mut self, client_id: u16, batch: u64) -> Result<Option<Client>, Error> {
+        Ok(self.snapshots_map.get(&client_id).and_then(|snapshots| {
+            snapshots
+                .iter()
+                .rev()
+                .find(|(snapshot_batch, _)| *snapshot_batch <= batch)
+                .map(|(_, client)| *client)
+        }))
+    }
+
+    async fn cursor(&mut self, source: &str, client_id: u16) -> Result<Option<Cursor>, Error> {
+        Ok(self
+            .cursors_map
+            .get(&(source.to_owned(), client_id))
+            .cloned())
+    }
+
+    async fn resume_point(&mut self, source: &str) -> Result<Option<ResumePoint>, Error> {
+        Ok(self.resume_points.get(source).copied())
+    }
+
+    async fn set_resume_point(&mut self, source: &str, point: ResumePoint) -> Result<(), Error> {
+        self.resume_points.insert(source.to_owned(), point);
+        Ok(())
+    }
+
+    async fn last_sequence(&mut self) -> Result<Option<u64>, Error> {
+        Ok(self.last_sequence)
+    }
+
+    async fn stream_clients(mut self, order: ClientOrder) -> mpsc::Receiver<Result<Client, Error>> {
         let receiver = self.clients_receiver.take().unwrap();
 
-        tokio::spawn(self.stream());
+        tokio::spawn(self.stream(order));
 
         receiver
     }
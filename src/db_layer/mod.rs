@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use std::ops::Range;
 use tokio::sync::mpsc;
 
 use super::*;
@@ -6,22 +7,109 @@ use super::*;
 pub mod hashmap;
 pub mod sled_db;
 
+/// The writes resulting from processing a single [`Transaction`], which must either all be applied
+/// or not be applied at all
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct WriteBatch {
+    /// The new or updated transactions to store
+    pub transactions: Vec<Transaction>,
+
+    /// The new or updated clients to store
+    pub clients: Vec<Client>,
+
+    /// The entries to append to the audit log, whose IDs are assigned when written
+    pub audit: Vec<AuditEntry>,
+
+    /// Where the transaction was read from, which becomes the cursor of its client for its source
+    pub cursor: Option<(u16, Cursor)>,
+}
+
+/// The order in which [`DbLayer::stream_clients`] streams clients
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ClientOrder {
+    /// By ascending client ID
+    Id,
+    /// By descending total funds, then by ascending client ID
+    Balance,
+}
+
+impl ClientOrder {
+    /// Compare two clients by this order
+    pub fn compare(self, a: &Client, b: &Client) -> std::cmp::Ordering {
+        match self {
+            ClientOrder::Id => a.client.cmp(&b.client),
+            ClientOrder::Balance => b.total.cmp(&a.total).then(a.client.cmp(&b.client)),
+        }
+    }
+}
+
+impl std::str::FromStr for ClientOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ClientOrder, String> {
+        match s {
+            "id" => Ok(ClientOrder::Id),
+            "balance" => Ok(ClientOrder::Balance),
+            other => Err(format!(
+                "unknown client order `{}`, expected one of `id`, `balance`",
+                other
+            )),
+        }
+    }
+}
+
+/// The shard, out of `shards` shards, which holds the state of a client when processing is split
+/// between several workers, each with its own `DbLayer`
+pub fn shard_of(client: u16, shards: usize) -> usize {
+    client as usize % shards
+}
+
 /// The layer which stores `Client`s, processes `Transaction`s, and streams the stored `Client`s
 /// after all `Transaction`s  have been processed
 #[async_trait]
 pub trait DbLayer {
-    /// Write a single transaction to the DbLayer implementor
-    async fn write_transaction(&mut self, transaction: Transaction) -> Result<(), Error>;
+    /// Atomically write every transaction and client of a [`WriteBatch`] to the DbLayer implementor
+    async fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error>;
 
     /// Get a single transaction from the DBLayer implementor
     async fn get_transaction(&mut self, transaction_id: u32) -> Result<Option<Transaction>, Error>;
 
-    /// Write a single client to the DbLayer implementor
-    async fn write_client(&mut self, client: Client) -> Result<(), Error>;
-
     /// Get a single client from the DbLayer implementor
     async fn get_client(&mut self, client_id: u16) -> Result<Option<Client>, Error>;
 
-    /// Return a [`mpsc::Receiver`] which streams all of the stored `Client`s for outputting data
-    async fn stream_clients(self) -> mpsc::Receiver<Result<Client, Error>>;
+    /// Get the audit log of a single client for the given range of timestamps, ordered by timestamp
+    /// and then by the order the entries were written. A `DbLayer` which doesn't outlive the run
+    /// may keep no audit log at all
+    async fn client_history(
+        &mut self,
+        client_id: u16,
+        timestamps: Range<u64>,
+    ) -> Result<Vec<AuditEntry>, Error>;
+
+    /// Record the state of every client at the end of the given batch, which must come after every
+    /// batch recorded before. Only clients which changed since the last snapshot need to be stored
+    async fn snapshot(&mut self, batch: u64) -> Result<(), Error>;
+
+    /// Get the number of the last batch recorded with [`DbLayer::snapshot`] if there is any
+    async fn last_batch(&mut self) -> Result<Option<u64>, Error>;
+
+    /// Get a single client as it was at the end of the given batch, or None if it had no applied
+    /// transactions by then
+    async fn client_as_of(&mut self, client_id: u16, batch: u64) -> Result<Option<Client>, Error>;
+
+    /// Get the cursor of the last transaction of a client read from the given source
+    async fn cursor(&mut self, source: &str, client_id: u16) -> Result<Option<Cursor>, Error>;
+
+    /// Get the [`ResumePoint`] last stored for the given source if there is any
+    async fn resume_point(&mut self, source: &str) -> Result<Option<ResumePoint>, Error>;
+
+    /// Store the [`ResumePoint`] of the given source, replacing any stored before
+    async fn set_resume_point(&mut self, source: &str, point: ResumePoint) -> Result<(), Error>;
+
+    /// Get the highest sequence number of any cursor
+    async fn last_sequence(&mut self) -> Result<Option<u64>, Error>;
+
+    /// Return a [`mpsc::Receiver`] which streams all of the stored `Client`s for outputting data in
+    /// the given order. Any error is streamed ahead of the clients
+    async fn stream_clients(self, order: ClientOrder) -> mpsc::Receiver<Result<Client, Error>>;
 }
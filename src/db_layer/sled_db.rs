@@ -1,22 +1,72 @@
 use async_trait::async_trait;
-use sled::Db;
-use std::path::Path;
+use serde::Deserialize;
+use sled::{transaction::TransactionError, Db, Transactional};
+use std::{convert::TryInto, ops::Range, path::Path};
 
 use super::*;
+use crate::transaction_processing::Policy;
+
+/// The version of the layout of the data stored in the database. Databases without a version were
+/// written before [`DisputeState`] replaced the `disputed` flag of a [`Transaction`]
+const SCHEMA_VERSION: u32 = 1;
+
+/// The layout of a [`Transaction`] as written before [`SCHEMA_VERSION`] 1
+#[derive(Deserialize)]
+struct LegacyTransaction {
+    ty: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<i64>,
+    disputed: bool,
+}
+
+impl From<LegacyTransaction> for Transaction {
+    fn from(transaction: LegacyTransaction) -> Transaction {
+        // Resolved and charged back transactions were indistinguishable from undisputed ones
+        let state = if transaction.disputed {
+            DisputeState::Disputed
+        } else {
+            DisputeState::Normal
+        };
+
+        Transaction {
+            ty: transaction.ty,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            state,
+        }
+    }
+}
 
 pub struct SledDb {
     db: Db,
+
+    /// The shard whose clients are streamed by this `SledDb`, out of [`SledDb::shards`] shards
+    shard: usize,
+    shards: usize,
+
     clients_sender: mpsc::Sender<Result<Client, Error>>,
     clients_receiver: Option<mpsc::Receiver<Result<Client, Error>>>,
 }
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>, buffer_size: usize) -> Result<SledDb, sled::Error> {
-        let db = sled::open(&path)?;
+        Self::from_db(sled::open(&path)?, buffer_size)
+    }
 
+    /// Use an already opened [`sled::Db`], migrating any data written by older versions
+    fn from_db(db: Db, buffer_size: usize) -> Result<SledDb, sled::Error> {
         // Create the keyspaces
         let _ = db.open_tree(b"transactions")?;
         let _ = db.open_tree(b"clients")?;
+        let _ = db.open_tree(b"meta")?;
+        let _ = db.open_tree(b"audit")?;
+        let _ = db.open_tree(b"snapshots")?;
+        let _ = db.open_tree(b"log")?;
+        let _ = db.open_tree(b"cursors")?;
+
+        Self::migrate(&db)?;
 
         let (clients_sender, clients_receiver) = mpsc::channel(buffer_size);
         let clients_receiver = Some(clients_receiver);
@@ -25,50 +75,204 @@ impl SledDb {
 
         Ok(SledDb {
             db,
+            shard: 0,
+            shards: 1,
             clients_sender,
             clients_receiver,
         })
     }
 
-    // This code is complicated because the `ColumnFamily`s
-    async fn stream(self) {
-        // FIXME: Eliminate unwraps
-        let tree = self.db.open_tree(b"clients").unwrap();
+    /// Split the database into `count` handles sharing the same trees such that each may be owned
+    /// by its own worker. Every handle only streams the clients of its shard as given by
+    /// [`shard_of`], so streaming from every handle yields every client once
+    pub fn shards(&self, count: usize, buffer_size: usize) -> Vec<SledDb> {
+        (0..count)
+            .map(|shard| {
+                let (clients_sender, clients_receiver) = mpsc::channel(buffer_size);
+
+                SledDb {
+                    db: self.db.clone(),
+                    shard,
+                    shards: count,
+                    clients_sender,
+                    clients_receiver: Some(clients_receiver),
+                }
+            })
+            .collect()
+    }
+
+    /// Store the [`Policy`] transactions are processed with unless the database already holds one,
+    /// returning the policy the database holds afterwards
+    pub fn claim_policy(&self, policy: &Policy) -> Result<Policy, Error> {
+        let tree = self.db.open_tree("meta")?;
+        let serialized = bincode::serialize(policy)
+            .map_err(|e| Error::DbLayer(format!("Error serializing: {}", e)))?;
+        match tree.compare_and_swap(b"policy", None as Option<&[u8]>, Some(serialized))? {
+            Ok(()) => {
+                self.db.flush()?;
+                Ok(*policy)
+            }
+            Err(sled::CompareAndSwapError { current, .. }) => {
+                let current = current.unwrap_or_default();
+                bincode::deserialize(&current)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            }
+        }
+    }
+
+    /// The [`Policy`] stored with [`SledDb::claim_policy`] if any
+    pub fn policy(&self) -> Result<Option<Policy>, Error> {
+        let tree = self.db.open_tree("meta")?;
+        tree.get(b"policy")?
+            .map(|bytes| {
+                bincode::deserialize(&bytes)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            })
+            .transpose()
+    }
+
+    /// Every transaction processed against the database in the order it was processed, whether it
+    /// was applied or refused. The transactions of a client are always in the order they were read
+    pub fn logged_transactions(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<Transaction, Error>>, Error> {
+        let tree = self.db.open_tree("log")?;
+        Ok(tree.iter().map(|result| {
+            let (_key, value) = result?;
+            bincode::deserialize(&value)
+                .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+        }))
+    }
+
+    /// Bring the data stored in the database up to the current [`SCHEMA_VERSION`]. The migration is
+    /// applied in a single transaction such that it is never left half done
+    fn migrate(db: &Db) -> Result<(), sled::Error> {
+        let transactions = db.open_tree(b"transactions")?;
+        let meta = db.open_tree(b"meta")?;
+
+        if meta.get(b"version")?.is_some() {
+            return Ok(());
+        }
+
+        let mut migrated = Vec::new();
+        for result in transactions.iter() {
+            let (key, value) = result?;
+            let migration_error =
+                |e| sled::Error::Unsupported(format!("Error migrating transaction: {}", e));
+            let transaction: Transaction = bincode::deserialize::<LegacyTransaction>(&value)
+                .map_err(migration_error)?
+                .into();
+            let value = bincode::serialize(&transaction).map_err(migration_error)?;
+            migrated.push((key, value));
+        }
+
+        let result: Result<(), TransactionError<()>> =
+            (&transactions, &meta).transaction(|(transactions, meta)| {
+                for (key, value) in migrated.iter() {
+                    transactions.insert(key, value.as_slice())?;
+                }
+                meta.insert(b"version", &SCHEMA_VERSION.to_le_bytes())?;
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Storage(e)) => Err(e),
+            Err(TransactionError::Abort(())) => unreachable!("the migration never aborts"),
+        }
+    }
+
+    /// Stream every client of this shard in the given order. As there are at most `u16::MAX`
+    /// clients they are sorted in memory, with any error sent as soon as it is encountered
+    async fn stream(self, order: ClientOrder) {
+        let tree = match self.db.open_tree(b"clients") {
+            Ok(tree) => tree,
+            Err(e) => {
+                let _ = self.clients_sender.send(Err(e.into())).await;
+                return;
+            }
+        };
+
+        let mut clients = Vec::new();
         for result in tree.iter() {
-            match result {
-                Ok(client) => match bincode::deserialize::<Client>(&client.1) {
-                    Ok(client) => {
-                        if self.clients_sender.send(Ok(client)).await.is_err() {
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        if self
-                            .clients_sender
-                            .send(Err(Error::DbLayer(format!("Error deserializing: {}", e))))
-                            .await
-                            .is_err()
-                        {
-                            break;
-                        }
-                    }
-                },
+            let client = result.map_err(Error::from).and_then(|(_key, value)| {
+                bincode::deserialize::<Client>(&value)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            });
 
+            match client {
+                Ok(client) if shard_of(client.client, self.shards) != self.shard => {}
+                Ok(client) => clients.push(client),
                 Err(e) => {
-                    if self
-                        .clients_sender
-                        .send(Err(Error::DbLayer(format!("{}", e))))
-                        .await
-                        .is_err()
-                    {
-                        break;
+                    if self.clients_sender.send(Err(e)).await.is_err() {
+                        return;
                     }
                 }
             }
         }
+        clients.sort_unstable_by(|a, b| order.compare(a, b));
+
+        for client in clients {
+            // Stop if the receiver has been closed as nothing is listening anymore
+            if self.clients_sender.send(Ok(client)).await.is_err() {
+                break;
+            }
+        }
     }
 }
 
+/// The key of an entry of the audit log, made up of big endian integers such that the entries of a
+/// client are ordered by timestamp and then by ID
+fn audit_key(client_id: u16, timestamp: u64, id: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(18);
+    key.extend_from_slice(&client_id.to_be_bytes());
+    key.extend_from_slice(&timestamp.to_be_bytes());
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// The key of a snapshot of a client, made up of big endian integers such that the snapshots of a
+/// client are ordered by batch
+fn snapshot_key(client_id: u16, batch: u64) -> [u8; 10] {
+    let mut key = [0; 10];
+    key[..2].copy_from_slice(&client_id.to_be_bytes());
+    key[2..].copy_from_slice(&batch.to_be_bytes());
+    key
+}
+
+/// The prefix of the keys of the cursors of a source: the length of its identity followed by the
+/// identity itself, such that no source's prefix is the prefix of another's
+fn cursor_prefix(source: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + source.len());
+    prefix.extend_from_slice(&(source.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(source.as_bytes());
+    prefix
+}
+
+/// The key of the cursor of a client for a source
+fn cursor_key(source: &str, client_id: u16) -> Vec<u8> {
+    let mut key = cursor_prefix(source);
+    key.extend_from_slice(&client_id.to_be_bytes());
+    key
+}
+
+/// The key of the resume point of a source in the meta tree
+fn resume_point_key(source: &str) -> Vec<u8> {
+    let mut key = b"resume".to_vec();
+    key.extend_from_slice(&cursor_prefix(source));
+    key
+}
+
+/// Decode a big endian `u64` as stored in the meta tree
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn deserialize_cursor(bytes: &[u8]) -> Result<Cursor, Error> {
+    bincode::deserialize(bytes).map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+}
+
 impl From<sled::Error> for Error {
     fn from(e: sled::Error) -> Error {
         Error::DbLayer(format!("{}", e))
@@ -77,53 +281,586 @@ impl From<sled::Error> for Error {
 
 #[async_trait]
 impl DbLayer for SledDb {
-    async fn write_transaction(&mut self, transaction: Transaction) -> Result<(), Error> {
-        let tree = self.db.open_tree("transactions")?;
-        tree.insert(
-            transaction.tx.to_le_bytes(),
-            bincode::serialize(&transaction).unwrap(),
-        )?;
+    async fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        // Everything is serialized ahead of the sled transaction as the closure may be retried
+        let serialize_error = |e| Error::DbLayer(format!("Error serializing: {}", e));
+        let transactions = batch
+            .transactions
+            .iter()
+            .map(|transaction| {
+                bincode::serialize(transaction)
+                    .map(|bytes| (transaction.tx.to_le_bytes(), bytes))
+                    .map_err(serialize_error)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let clients = batch
+            .clients
+            .iter()
+            .map(|client| {
+                bincode::serialize(client)
+                    .map(|bytes| (client.client.to_le_bytes(), bytes))
+                    .map_err(serialize_error)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let audit = batch
+            .audit
+            .into_iter()
+            .map(|mut entry| {
+                entry.id = self.db.generate_id()?;
+                let key = audit_key(entry.transaction.client, entry.timestamp, entry.id);
+                let value = bincode::serialize(&entry).map_err(serialize_error)?;
+                let logged = bincode::serialize(&entry.transaction).map_err(serialize_error)?;
+                Ok((key, value, entry.id.to_be_bytes(), logged))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let cursor = batch
+            .cursor
+            .map(|(client_id, cursor)| {
+                bincode::serialize(&cursor)
+                    .map(|bytes| {
+                        (
+                            cursor_key(&cursor.source, client_id),
+                            bytes,
+                            cursor.sequence,
+                        )
+                    })
+                    .map_err(serialize_error)
+            })
+            .transpose()?;
+
+        let transactions_tree = self.db.open_tree("transactions")?;
+        let clients_tree = self.db.open_tree("clients")?;
+        let audit_tree = self.db.open_tree("audit")?;
+        let log_tree = self.db.open_tree("log")?;
+        let cursors_tree = self.db.open_tree("cursors")?;
+        let meta_tree = self.db.open_tree("meta")?;
+        let result: Result<(), TransactionError<()>> = (
+            &transactions_tree,
+            &clients_tree,
+            &audit_tree,
+            &log_tree,
+            &cursors_tree,
+            &meta_tree,
+        )
+            .transaction(
+                |(
+                    transactions_tree,
+                    clients_tree,
+                    audit_tree,
+                    log_tree,
+                    cursors_tree,
+                    meta_tree,
+                )| {
+                    for (key, value) in transactions.iter() {
+                        transactions_tree.insert(key, value.as_slice())?;
+                    }
+                    for (key, value) in clients.iter() {
+                        clients_tree.insert(key, value.as_slice())?;
+                    }
+                    // The log holds the processed transactions ordered by their audit entry's ID
+                    for (key, value, log_key, logged) in audit.iter() {
+                        audit_tree.insert(key.as_slice(), value.as_slice())?;
+                        log_tree.insert(log_key, logged.as_slice())?;
+                    }
+                    // The highest sequence number is kept alongside such that it is never looked for
+                    if let Some((key, value, sequence)) = cursor.as_ref() {
+                        cursors_tree.insert(key.as_slice(), value.as_slice())?;
+                        let last = meta_tree
+                            .get(b"sequence")?
+                            .and_then(|bytes| decode_u64(&bytes));
+                        if last.is_none_or(|last| last < *sequence) {
+                            meta_tree.insert(b"sequence", &sequence.to_be_bytes())?;
+                        }
+                    }
+                    Ok(())
+                },
+            );
+
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+            Err(TransactionError::Abort(())) => unreachable!("writing a batch never aborts"),
+        }
+
         self.db.flush()?;
         Ok(())
     }
+
     async fn get_transaction(&mut self, transaction_id: u32) -> Result<Option<Transaction>, Error> {
         let tree = self.db.open_tree("transactions")?;
-        if let Some(Ok(transaction)) = tree
-            .get(transaction_id.to_le_bytes())?
-            .map(|bytes| bincode::deserialize(&bytes))
-        {
-            Ok(Some(transaction))
-        } else {
-            Ok(None)
-        }
+        tree.get(transaction_id.to_le_bytes())?
+            .map(|bytes| {
+                bincode::deserialize(&bytes)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            })
+            .transpose()
     }
 
-    async fn write_client(&mut self, client: Client) -> Result<(), Error> {
+    async fn get_client(&mut self, client_id: u16) -> Result<Option<Client>, Error> {
         let tree = self.db.open_tree("clients")?;
-        tree.insert(
-            client.client.to_le_bytes(),
-            bincode::serialize(&client).unwrap(),
-        )?;
+        tree.get(client_id.to_le_bytes())?
+            .map(|bytes| {
+                bincode::deserialize(&bytes)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            })
+            .transpose()
+    }
+
+    async fn client_history(
+        &mut self,
+        client_id: u16,
+        timestamps: Range<u64>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let tree = self.db.open_tree("audit")?;
+        let start = audit_key(client_id, timestamps.start, 0);
+        let end = audit_key(client_id, timestamps.end, 0);
+
+        tree.range(start..end)
+            .map(|result| {
+                let (_key, value) = result?;
+                bincode::deserialize(&value)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            })
+            .collect()
+    }
+
+    async fn snapshot(&mut self, batch: u64) -> Result<(), Error> {
+        let clients_tree = self.db.open_tree("clients")?;
+        let snapshots_tree = self.db.open_tree("snapshots")?;
+        let meta_tree = self.db.open_tree("meta")?;
+
+        // Only the clients of this shard which differ from their last snapshot are recorded
+        let mut snapshots = Vec::new();
+        for result in clients_tree.iter() {
+            let (_key, value) = result?;
+            let client: Client = bincode::deserialize(&value)
+                .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))?;
+            if shard_of(client.client, self.shards) != self.shard {
+                continue;
+            }
+
+            let last = snapshots_tree
+                .range(snapshot_key(client.client, 0)..=snapshot_key(client.client, batch))
+                .next_back()
+                .transpose()?;
+            if last.is_none_or(|(_key, last)| *last != *value) {
+                snapshots.push((snapshot_key(client.client, batch), value));
+            }
+        }
+
+        let result: Result<(), TransactionError<()>> =
+            (&snapshots_tree, &meta_tree).transaction(|(snapshots_tree, meta_tree)| {
+                for (key, value) in snapshots.iter() {
+                    snapshots_tree.insert(key.as_slice(), value.clone())?;
+                }
+                meta_tree.insert(b"batch", &batch.to_be_bytes())?;
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+            Err(TransactionError::Abort(())) => unreachable!("a snapshot never aborts"),
+        }
+
         self.db.flush()?;
         Ok(())
     }
-    async fn get_client(&mut self, client_id: u16) -> Result<Option<Client>, Error> {
-        let tree = self.db.open_tree("clients")?;
-        if let Some(Ok(client)) = tree
-            .get(client_id.to_le_bytes())?
-            .map(|bytes| bincode::deserialize(&bytes))
+
+    async fn last_batch(&mut self) -> Result<Option<u64>, Error> {
+        let tree = self.db.open_tree("meta")?;
+        Ok(tree.get(b"batch")?.and_then(|bytes| decode_u64(&bytes)))
+    }
+
+    async fn client_as_of(&mut self, client_id: u16, batch: u64) -> Result<Option<Client>, Error> {
+        let tree = self.db.open_tree("snapshots")?;
+        match tree
+            .range(snapshot_key(client_id, 0)..=snapshot_key(client_id, batch))
+            .next_back()
+            .transpose()?
         {
-            Ok(Some(client))
-        } else {
-            Ok(None)
+            Some((_key, value)) => bincode::deserialize(&value)
+                .map(Some)
+                .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e))),
+            None => Ok(None),
         }
     }
 
-    async fn stream_clients(mut self) -> mpsc::Receiver<Result<Client, Error>> {
+    async fn cursor(&mut self, source: &str, client_id: u16) -> Result<Option<Cursor>, Error> {
+        let tree = self.db.open_tree("cursors")?;
+        tree.get(cursor_key(source, client_id))?
+            .map(|bytes| deserialize_cursor(&bytes))
+            .transpose()
+    }
+
+    async fn resume_point(&mut self, source: &str) -> Result<Option<ResumePoint>, Error> {
+        let tree = self.db.open_tree("meta")?;
+        tree.get(resume_point_key(source))?
+            .map(|bytes| {
+                bincode::deserialize(&bytes)
+                    .map_err(|e| Error::DbLayer(format!("Error deserializing: {}", e)))
+            })
+            .transpose()
+    }
+
+    async fn set_resume_point(&mut self, source: &str, point: ResumePoint) -> Result<(), Error> {
+        let tree = self.db.open_tree("meta")?;
+        let value = bincode::serialize(&point)
+            .map_err(|e| Error::DbLayer(format!("Error serializing: {}", e)))?;
+        tree.insert(resume_point_key(source), value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    async fn last_sequence(&mut self) -> Result<Option<u64>, Error> {
+        let tree = self.db.open_tree("meta")?;
+        Ok(tree.get(b"sequence")?.and_then(|bytes| decode_u64(&bytes)))
+    }
+
+    async fn stream_clients(mut self, order: ClientOrder) -> mpsc::Receiver<Result<Client, Error>> {
         let receiver = self.clients_receiver.take().unwrap();
 
-        tokio::spawn(self.stream());
+        tokio::spawn(self.stream(order));
 
         receiver
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Serialize;
+    use tempfile::TempDir;
+
+    #[derive(Serialize)]
+    struct LegacyTransaction {
+        ty: TransactionType,
+        client: u16,
+        tx: u32,
+        amount: Option<i64>,
+        disputed: bool,
+    }
+
+    #[tokio::test]
+    async fn migrate_legacy_transactions() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("database");
+
+        // Write transactions as they were laid out before the `DisputeState` was introduced. The
+        // database is handed over to the `SledDb` as sled holds its lock for a while after a drop
+        let db = sled::open(&path).unwrap();
+        {
+            let tree = db.open_tree(b"transactions").unwrap();
+            for (tx, disputed) in [(1u32, false), (2u32, true)] {
+                let transaction = LegacyTransaction {
+                    ty: TransactionType::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(10000),
+                    disputed,
+                };
+                tree.insert(tx.to_le_bytes(), bincode::serialize(&transaction).unwrap())
+                    .unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        let mut db_layer = SledDb::from_db(db, 2).unwrap();
+
+        let expected = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        assert_eq!(Some(expected), db_layer.get_transaction(1).await.unwrap());
+        assert_eq!(
+            Some(Transaction {
+                tx: 2,
+                state: DisputeState::Disputed,
+                ..expected
+            }),
+            db_layer.get_transaction(2).await.unwrap()
+        );
+
+        // Migrating the database again must leave the migrated transactions alone
+        SledDb::migrate(&db_layer.db).unwrap();
+        assert_eq!(Some(expected), db_layer.get_transaction(1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_batch() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+
+        let transaction = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let client = Client {
+            client: 1,
+            available: 10000,
+            held: 0,
+            total: 10000,
+            locked: false,
+        };
+
+        db_layer
+            .write_batch(WriteBatch {
+                transactions: vec![transaction],
+                clients: vec![client],
+                ..WriteBatch::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Some(transaction),
+            db_layer.get_transaction(1).await.unwrap()
+        );
+        assert_eq!(Some(client), db_layer.get_client(1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn last_sequence() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+        assert_eq!(None, db_layer.last_sequence().await.unwrap());
+
+        // Cursors of different workers may be written out of order
+        for (client, sequence) in [(1, 7), (2, 3)] {
+            let cursor = Cursor {
+                sequence,
+                source: "test.csv".to_owned(),
+                offset: None,
+            };
+            db_layer
+                .write_batch(WriteBatch {
+                    cursor: Some((client, cursor)),
+                    ..WriteBatch::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(Some(7), db_layer.last_sequence().await.unwrap());
+        assert_eq!(
+            Some(3),
+            db_layer
+                .cursor("test.csv", 2)
+                .await
+                .unwrap()
+                .map(|cursor| cursor.sequence)
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_point() {
+        let dir = TempDir::new_in("./").unwrap();
+        let db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+        let mut shards = db_layer.shards(2, 2);
+        assert_eq!(None, shards[0].resume_point("test.csv").await.unwrap());
+
+        // A resume point stored through one handle is seen by every other
+        let point = ResumePoint {
+            sequence: 12,
+            position: Position {
+                offset: 340,
+                fingerprint: 7,
+            },
+        };
+        shards[0].set_resume_point("test.csv", point).await.unwrap();
+        assert_eq!(
+            Some(point),
+            shards[1].resume_point("test.csv").await.unwrap()
+        );
+        assert_eq!(None, shards[1].resume_point("test").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn policy_is_kept() {
+        let dir = TempDir::new_in("./").unwrap();
+        let db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+        assert_eq!(None, db_layer.policy().unwrap());
+
+        let policy = Policy {
+            redispute_resolved: true,
+            ..Policy::default()
+        };
+        assert_eq!(policy, db_layer.claim_policy(&policy).unwrap());
+
+        // The policy claimed first stays
+        assert_eq!(policy, db_layer.claim_policy(&Policy::default()).unwrap());
+        assert_eq!(Some(policy), db_layer.policy().unwrap());
+    }
+
+    #[tokio::test]
+    async fn corrupted_values() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+
+        // Values which can't be decoded are reported rather than read as missing
+        db_layer
+            .db
+            .open_tree("transactions")
+            .unwrap()
+            .insert(1u32.to_le_bytes(), &[1u8][..])
+            .unwrap();
+        db_layer
+            .db
+            .open_tree("clients")
+            .unwrap()
+            .insert(1u16.to_le_bytes(), &[1u8][..])
+            .unwrap();
+
+        assert!(matches!(
+            db_layer.get_transaction(1).await,
+            Err(Error::DbLayer(_))
+        ));
+        assert!(matches!(
+            db_layer.get_client(1).await,
+            Err(Error::DbLayer(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_history() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+
+        let client = Client {
+            client: 1,
+            available: 0,
+            held: 0,
+            total: 0,
+            locked: false,
+        };
+        let entry = |client_id, timestamp| AuditEntry {
+            id: 0,
+            timestamp,
+            transaction: Transaction {
+                ty: TransactionType::Deposit,
+                client: client_id,
+                tx: 1,
+                amount: Some(10000),
+                state: DisputeState::Normal,
+            },
+            error: None,
+            before: client,
+            after: client,
+        };
+
+        // Entries are written out of order and for several clients
+        db_layer
+            .write_batch(WriteBatch {
+                audit: vec![entry(1, 300), entry(1, 100), entry(2, 200), entry(1, 200)],
+                ..WriteBatch::default()
+            })
+            .await
+            .unwrap();
+
+        let timestamps = |history: Vec<AuditEntry>| {
+            history
+                .iter()
+                .map(|entry| entry.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![100, 200, 300],
+            timestamps(db_layer.client_history(1, 0..u64::MAX).await.unwrap())
+        );
+        assert_eq!(
+            vec![200],
+            timestamps(db_layer.client_history(1, 101..300).await.unwrap())
+        );
+        assert_eq!(
+            vec![200],
+            timestamps(db_layer.client_history(2, 0..u64::MAX).await.unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots() {
+        let dir = TempDir::new_in("./").unwrap();
+
+        let client = |client_id, total| Client {
+            client: client_id,
+            available: total,
+            held: 0,
+            total,
+            locked: false,
+        };
+        let batch = |clients| WriteBatch {
+            clients,
+            ..WriteBatch::default()
+        };
+
+        let mut shards = SledDb::new(dir.path().join("database"), 2)
+            .unwrap()
+            .shards(2, 2);
+        assert_eq!(None, shards[0].last_batch().await.unwrap());
+
+        shards[0]
+            .write_batch(batch(vec![client(2, 100)]))
+            .await
+            .unwrap();
+        shards[1]
+            .write_batch(batch(vec![client(1, 100)]))
+            .await
+            .unwrap();
+        for shard in shards.iter_mut() {
+            shard.snapshot(0).await.unwrap();
+        }
+
+        shards[1]
+            .write_batch(batch(vec![client(1, 300)]))
+            .await
+            .unwrap();
+        for shard in shards.iter_mut() {
+            shard.snapshot(1).await.unwrap();
+        }
+
+        // Later batches leave earlier snapshots untouched
+        let db_layer = &mut shards[0];
+        db_layer
+            .write_batch(batch(vec![client(2, 500)]))
+            .await
+            .unwrap();
+        assert_eq!(Some(1), db_layer.last_batch().await.unwrap());
+        db_layer.snapshot(2).await.unwrap();
+        assert_eq!(Some(2), db_layer.last_batch().await.unwrap());
+
+        assert_eq!(
+            Some(client(1, 100)),
+            db_layer.client_as_of(1, 0).await.unwrap()
+        );
+        assert_eq!(
+            Some(client(1, 300)),
+            db_layer.client_as_of(1, 1).await.unwrap()
+        );
+        assert_eq!(
+            Some(client(1, 300)),
+            db_layer.client_as_of(1, 2).await.unwrap()
+        );
+        assert_eq!(
+            Some(client(2, 100)),
+            db_layer.client_as_of(2, 1).await.unwrap()
+        );
+        assert_eq!(
+            Some(client(2, 500)),
+            db_layer.client_as_of(2, 7).await.unwrap()
+        );
+        assert_eq!(None, db_layer.client_as_of(3, 2).await.unwrap());
+
+        // Only clients which changed are recorded again
+        let tree = db_layer.db.open_tree("snapshots").unwrap();
+        assert_eq!(4, tree.len());
+    }
+}
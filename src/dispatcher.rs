@@ -0,0 +1,730 @@
+use std::collections::HashMap;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    db_layer::{shard_of, DbLayer},
+    reader::{ReadError, Reply, Submission},
+    transaction_processing::{self, Policy},
+    Cursor, Error, ResumePoint, Transaction, TransactionType,
+};
+
+/// What became of a single [`Submission`] once it was processed
+#[derive(Debug)]
+pub enum Outcome {
+    /// The record could not be read as a transaction
+    Malformed {
+        error: ReadError,
+        reply: Option<Reply>,
+    },
+
+    /// The transaction was applied if `result` is Ok or refused otherwise
+    Processed {
+        transaction: Transaction,
+        result: Result<(), Error>,
+        reply: Option<Reply>,
+    },
+
+    /// The transaction was read again from a source it had already been processed from, so it was
+    /// left alone. Whoever sent it before has already been told what became of it
+    Skipped,
+}
+
+/// The source every transaction is read from and the sequence number given to its first record
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Source {
+    /// The identity stored in the [`Cursor`] of every transaction, such as the canonical path of a
+    /// file
+    pub identity: String,
+
+    pub first_sequence: u64,
+}
+
+/// What the dispatcher hands back once every worker is done
+pub struct Finished<D> {
+    /// The `DbLayer` of every worker, in the order they were given
+    pub db_layers: Vec<D>,
+
+    /// The [`ResumePoint`] right after the last record routed to a worker, if the source can be
+    /// read from an offset
+    pub reached: Option<ResumePoint>,
+}
+
+/// The work sent from the dispatcher to a single worker
+enum Job {
+    /// Process a transaction of one of the worker's clients unless it was already processed
+    Process(Transaction, Cursor, Option<Reply>),
+
+    /// Refuse a transaction of one of the worker's clients with an error found by the dispatcher
+    /// unless it was already processed, recording it as any other refused transaction
+    Refuse(Transaction, Cursor, Option<Reply>, Error),
+
+    /// Report a record which could not be read as a transaction once every earlier job has been
+    /// processed
+    Malformed(ReadError, Option<Reply>),
+
+    /// Report whether the worker holds a transaction once every earlier job has been processed
+    Contains(u32, oneshot::Sender<Result<bool, Error>>),
+}
+
+/// Process every transaction from the receiver on one worker task per given [`DbLayer`], sending
+/// what became of each to the returned [`mpsc::Receiver`]. Transactions are routed to a worker by
+/// their client with [`shard_of`], so the transactions of a client are processed in the order they
+/// were read while different clients are processed in parallel. Once the receiver is closed every
+/// `DbLayer` is returned through the [`JoinHandle`] such that the clients may be streamed. Once every
+/// outcome has been handled without a failing `DbLayer`, every record before [`Finished::reached`]
+/// has been processed
+///
+/// Transaction IDs are shared between clients, so a deposit or withdrawal reusing the ID of a
+/// transaction held by another worker is refused with [`Error::DuplicateTransaction`] and a dispute,
+/// resolve, or chargeback referencing one is refused with [`Error::ReferencesWrongClient`]. Either
+/// way the refusal is recorded by the worker of the transaction's client, and the final state of
+/// every client as well as its audit log are exactly the ones reached when processing sequentially
+///
+/// Every record is given the next sequence number of the [`Source`], malformed ones included, and
+/// the [`Cursor`] of each transaction is stored along with it. A transaction whose client already
+/// has a cursor for the source with the same or a later sequence number is skipped, such that a
+/// source read again from the start or from a [`ResumePoint`] is never processed twice
+///
+/// A malformed record is reported by the worker which was sent the record before it, such that it
+/// is acknowledged after that record rather than overtaking it
+///
+/// If `strict` is set nothing is read past the first malformed record. Its [`Outcome::Malformed`]
+/// is only sent once every worker has processed every transaction read before it, such that what
+/// was processed when the run stops doesn't depend on the number of workers
+pub fn start<D: DbLayer + Send + 'static>(
+    receiver: mpsc::Receiver<Submission>,
+    db_layers: Vec<D>,
+    policy: Policy,
+    strict: bool,
+    source: Source,
+    buffer_size: usize,
+) -> (mpsc::Receiver<Outcome>, JoinHandle<Finished<D>>) {
+    let (outcomes_sender, outcomes_receiver) = mpsc::channel(buffer_size);
+
+    let mut jobs = Vec::with_capacity(db_layers.len());
+    let mut workers = Vec::with_capacity(db_layers.len());
+    for db_layer in db_layers {
+        let (jobs_sender, jobs_receiver) = mpsc::channel(buffer_size);
+        jobs.push(jobs_sender);
+        workers.push(tokio::spawn(work(
+            db_layer,
+            policy,
+            jobs_receiver,
+            outcomes_sender.clone(),
+        )));
+    }
+
+    let handle = tokio::spawn(async move {
+        let (malformed, reached) = dispatch(receiver, strict, source, jobs).await;
+
+        let mut db_layers = Vec::with_capacity(workers.len());
+        for worker in workers {
+            // A panicking worker is a bug, so the panic is carried on rather than handled
+            match worker.await {
+                Ok(db_layer) => db_layers.push(db_layer),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+
+        if let Some(malformed) = malformed {
+            let _ = outcomes_sender.send(malformed).await;
+        }
+        Finished { db_layers, reached }
+    });
+
+    (outcomes_receiver, handle)
+}
+
+/// Route every submission to the worker of its client until the receiver is closed or nothing is
+/// listening for outcomes anymore, returning the [`ResumePoint`] right after the last record routed.
+/// In strict mode routing stops at the first malformed record, whose outcome is returned to be sent
+/// once the workers are done
+async fn dispatch(
+    mut receiver: mpsc::Receiver<Submission>,
+    strict: bool,
+    source: Source,
+    jobs: Vec<mpsc::Sender<Job>>,
+) -> (Option<Outcome>, Option<ResumePoint>) {
+    // The worker which most recently took each deposit or withdrawal ID. Only the worker which took
+    // an ID can hold a transaction with it, though it won't if the transaction was refused. With a
+    // single worker every ID is taken by it, so nothing needs to be tracked
+    let mut taken: HashMap<u32, usize> = HashMap::new();
+
+    // The worker which was sent the last record, which malformed records are queued behind
+    let mut last = 0;
+
+    let mut sequence = source.first_sequence;
+    let mut reached = None;
+    while let Some(Submission {
+        transaction,
+        reply,
+        position,
+    }) = receiver.recv().await
+    {
+        let cursor = Cursor {
+            sequence,
+            source: source.identity.clone(),
+            offset: position.map(|position| position.offset),
+        };
+        sequence += 1;
+
+        let transaction = match transaction {
+            Ok(transaction) => transaction,
+            Err(error) if strict => return (Some(Outcome::Malformed { error, reply }), reached),
+            Err(error) => {
+                if jobs[last].send(Job::Malformed(error, reply)).await.is_err() {
+                    break;
+                }
+                reached = position
+                    .map(|position| ResumePoint { sequence, position })
+                    .or(reached);
+                continue;
+            }
+        };
+
+        let shard = shard_of(transaction.client, jobs.len());
+        let other = match taken.get(&transaction.tx) {
+            Some(&other) if other != shard => Some(other),
+            _ => None,
+        };
+
+        // Only a transaction whose ID was taken by another worker needs more than its own worker,
+        // in which case that worker is asked whether it holds the transaction once it has caught up
+        let refusal = match other {
+            Some(other) => match contains(&jobs[other], transaction.tx).await {
+                Ok(true) if is_deposit_or_withdrawal(transaction.ty) => {
+                    Some(Error::DuplicateTransaction)
+                }
+                Ok(true) => Some(Error::ReferencesWrongClient),
+                Ok(false) => None,
+                Err(e) => Some(e),
+            },
+            None => None,
+        };
+
+        let job = match refusal {
+            Some(error) => Job::Refuse(transaction, cursor, reply, error),
+            None => {
+                if jobs.len() > 1 && is_deposit_or_withdrawal(transaction.ty) {
+                    taken.insert(transaction.tx, shard);
+                }
+                Job::Process(transaction, cursor, reply)
+            }
+        };
+        if jobs[shard].send(job).await.is_err() {
+            break;
+        }
+        last = shard;
+        reached = position
+            .map(|position| ResumePoint { sequence, position })
+            .or(reached);
+    }
+
+    (None, reached)
+}
+
+/// Ask a worker whether it holds a transaction
+async fn contains(jobs: &mpsc::Sender<Job>, transaction_id: u32) -> Result<bool, Error> {
+    let worker_stopped = || Error::DbLayer("a worker stopped unexpectedly".to_owned());
+
+    let (sender, receiver) = oneshot::channel();
+    jobs.send(Job::Contains(transaction_id, sender))
+        .await
+        .map_err(|_| worker_stopped())?;
+    receiver.await.map_err(|_| worker_stopped())?
+}
+
+fn is_deposit_or_withdrawal(ty: TransactionType) -> bool {
+    matches!(ty, TransactionType::Deposit | TransactionType::Withdrawal)
+}
+
+/// Process every job sent to a worker with its own [`DbLayer`], returning the `DbLayer` once the
+/// dispatcher is done or nothing is listening for outcomes anymore
+async fn work<D: DbLayer>(
+    mut db_layer: D,
+    policy: Policy,
+    mut jobs: mpsc::Receiver<Job>,
+    outcomes: mpsc::Sender<Outcome>,
+) -> D {
+    while let Some(job) = jobs.recv().await {
+        let (transaction, cursor, reply, refusal) = match job {
+            Job::Process(transaction, cursor, reply) => (transaction, cursor, reply, None),
+            Job::Refuse(transaction, cursor, reply, error) => {
+                (transaction, cursor, reply, Some(error))
+            }
+            Job::Malformed(error, reply) => {
+                if outcomes
+                    .send(Outcome::Malformed { error, reply })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            Job::Contains(transaction_id, sender) => {
+                let result = db_layer
+                    .get_transaction(transaction_id)
+                    .await
+                    .map(|transaction| transaction.is_some());
+                let _ = sender.send(result);
+                continue;
+            }
+        };
+
+        let outcome = match db_layer.cursor(&cursor.source, transaction.client).await {
+            Ok(Some(last)) if cursor.sequence <= last.sequence => Outcome::Skipped,
+            Ok(_) => {
+                let result = match refusal {
+                    Some(error) => {
+                        transaction_processing::refuse_ingested(
+                            &mut db_layer,
+                            transaction,
+                            cursor,
+                            error,
+                        )
+                        .await
+                    }
+                    None => {
+                        transaction_processing::process_ingested(
+                            &mut db_layer,
+                            &policy,
+                            transaction,
+                            cursor,
+                        )
+                        .await
+                    }
+                };
+                Outcome::Processed {
+                    transaction,
+                    result,
+                    reply,
+                }
+            }
+            Err(e) => Outcome::Processed {
+                transaction,
+                result: Err(e),
+                reply,
+            },
+        };
+        if outcomes.send(outcome).await.is_err() {
+            break;
+        }
+    }
+
+    db_layer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::{
+        db_layer::{hashmap::HashMapDb, sled_db::SledDb, ClientOrder},
+        Acknowledgement, Client, DisputeState, Position, Status,
+    };
+
+    fn transaction_type() -> impl Strategy<Value = TransactionType> {
+        prop_oneof![
+            3 => Just(TransactionType::Deposit),
+            2 => Just(TransactionType::Withdrawal),
+            1 => Just(TransactionType::Dispute),
+            1 => Just(TransactionType::Resolve),
+            1 => Just(TransactionType::Chargeback),
+        ]
+    }
+
+    /// Transactions drawn from few clients and IDs such that IDs are often shared between clients
+    fn transactions() -> impl Strategy<Value = Vec<Transaction>> {
+        let transaction = (transaction_type(), 1..8u16, 1..24u32, 1..50_000i64).prop_map(
+            |(ty, client, tx, amount)| Transaction {
+                ty,
+                client,
+                tx,
+                amount: is_deposit_or_withdrawal(ty).then_some(amount),
+                state: DisputeState::Normal,
+            },
+        );
+        prop::collection::vec(transaction, 0..200)
+    }
+
+    /// Every client streamed from the given `DbLayer`s ordered by ID
+    async fn clients(db_layers: Vec<HashMapDb>) -> Vec<Client> {
+        let mut clients = Vec::new();
+        for db_layer in db_layers {
+            let mut receiver = db_layer.stream_clients(ClientOrder::Id).await;
+            while let Some(client) = receiver.recv().await {
+                clients.push(client.unwrap());
+            }
+        }
+        clients.sort_by_key(|client| client.client);
+        clients
+    }
+
+    fn source() -> Source {
+        Source {
+            identity: "test".to_owned(),
+            first_sequence: 0,
+        }
+    }
+
+    async fn sequential(input: &[Transaction]) -> (Vec<Client>, Vec<bool>) {
+        let mut db_layer = HashMapDb::new(2);
+        let policy = Policy::default();
+        let mut accepted = Vec::new();
+        for transaction in input {
+            let result =
+                transaction_processing::process_transaction(&mut db_layer, &policy, *transaction)
+                    .await;
+            accepted.push(result.is_ok());
+        }
+        (clients(vec![db_layer]).await, accepted)
+    }
+
+    async fn sharded(input: &[Transaction], workers: usize) -> (Vec<Client>, Vec<bool>) {
+        let (sender, receiver) = mpsc::channel(input.len().max(1));
+        let db_layers = (0..workers).map(|_| HashMapDb::new(2)).collect();
+        let (mut outcomes, handle) =
+            start(receiver, db_layers, Policy::default(), false, source(), 2);
+
+        // Acknowledgements are used to tell which transaction each outcome belongs to
+        let mut replies = Vec::new();
+        for transaction in input {
+            let (reply, replies_receiver) = mpsc::channel(1);
+            let permit = reply.reserve_owned().await.unwrap();
+            sender
+                .send(Submission {
+                    transaction: Ok(*transaction),
+                    reply: Some(Reply { permit, line: 0 }),
+                    position: None,
+                })
+                .await
+                .unwrap();
+            replies.push(replies_receiver);
+        }
+        drop(sender);
+
+        while let Some(outcome) = outcomes.recv().await {
+            match outcome {
+                Outcome::Processed { result, reply, .. } => {
+                    let acknowledgement = match result {
+                        Ok(()) => Acknowledgement::accepted(0, 0),
+                        Err(e) => Acknowledgement::rejected(0, 0, &e),
+                    };
+                    reply.unwrap().send(acknowledgement);
+                }
+                Outcome::Malformed { .. } | Outcome::Skipped => {
+                    unreachable!("every transaction is well formed and read once")
+                }
+            }
+        }
+
+        let mut accepted = Vec::new();
+        for mut replies in replies {
+            let acknowledgement = replies.recv().await.unwrap();
+            accepted.push(acknowledgement.error.is_none());
+        }
+        (clients(handle.await.unwrap().db_layers).await, accepted)
+    }
+
+    #[tokio::test]
+    async fn skips_transactions_read_again() {
+        let input = [
+            (TransactionType::Deposit, 1, 1, Some(20000)),
+            (TransactionType::Deposit, 2, 2, Some(10000)),
+            (TransactionType::Withdrawal, 1, 3, Some(5000)),
+            (TransactionType::Dispute, 2, 2, None),
+        ]
+        .iter()
+        .map(|&(ty, client, tx, amount)| Transaction {
+            ty,
+            client,
+            tx,
+            amount,
+            state: DisputeState::Normal,
+        })
+        .collect::<Vec<_>>();
+
+        // Returns whether each transaction was processed rather than skipped, in no particular order
+        async fn ingest(
+            input: &[Transaction],
+            db_layers: Vec<HashMapDb>,
+            first_sequence: u64,
+        ) -> (Vec<bool>, Vec<HashMapDb>) {
+            let (sender, receiver) = mpsc::channel(input.len());
+            for transaction in input {
+                sender
+                    .send(Submission {
+                        transaction: Ok(*transaction),
+                        reply: None,
+                        position: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+            drop(sender);
+
+            let source = Source {
+                first_sequence,
+                ..source()
+            };
+            let (mut outcomes, handle) =
+                start(receiver, db_layers, Policy::default(), false, source, 2);
+            let mut processed = Vec::new();
+            while let Some(outcome) = outcomes.recv().await {
+                processed.push(matches!(outcome, Outcome::Processed { .. }));
+            }
+            (processed, handle.await.unwrap().db_layers)
+        }
+
+        let db_layers = (0..2).map(|_| HashMapDb::new(2)).collect();
+        let (processed, db_layers) = ingest(&input[..3], db_layers, 0).await;
+        assert_eq!(vec![true; 3], processed);
+
+        // Reading the source again from the second record only processes the record not yet seen
+        let (processed, db_layers) = ingest(&input[1..], db_layers, 1).await;
+        assert_eq!(1, processed.iter().filter(|&&processed| processed).count());
+
+        let (expected, _) = sequential(&input).await;
+        assert_eq!(expected, clients(db_layers).await);
+    }
+
+    #[tokio::test]
+    async fn reaches_the_last_record() {
+        let (sender, receiver) = mpsc::channel(3);
+        let position = |offset| Position {
+            offset,
+            fingerprint: offset,
+        };
+        for (tx, position) in [(1, Some(position(10))), (2, Some(position(20))), (3, None)] {
+            let transaction = Transaction {
+                ty: TransactionType::Deposit,
+                client: tx as u16,
+                tx,
+                amount: Some(10000),
+                state: DisputeState::Normal,
+            };
+            sender
+                .send(Submission {
+                    transaction: Ok(transaction),
+                    reply: None,
+                    position,
+                })
+                .await
+                .unwrap();
+        }
+        drop(sender);
+
+        let db_layers = (0..2).map(|_| HashMapDb::new(2)).collect();
+        let source = Source {
+            first_sequence: 5,
+            ..source()
+        };
+        let (mut outcomes, handle) =
+            start(receiver, db_layers, Policy::default(), false, source, 2);
+        while outcomes.recv().await.is_some() {}
+
+        // A record without a position leaves the last resume point as it is
+        let expected = ResumePoint {
+            sequence: 7,
+            position: position(20),
+        };
+        assert_eq!(Some(expected), handle.await.unwrap().reached);
+    }
+
+    #[tokio::test]
+    async fn acknowledges_malformed_records_in_order() {
+        let deposit = |tx| Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let malformed = ReadError {
+            line: 2,
+            record: "bad,row".to_owned(),
+            error: "malformed".into(),
+        };
+
+        // Every record shares a single reply channel the way the records of a connection do
+        let (reply, mut replies) = mpsc::channel(3);
+        let (sender, receiver) = mpsc::channel(3);
+        for (line, transaction) in (1..).zip([Ok(deposit(1)), Err(malformed), Ok(deposit(2))]) {
+            let permit = reply.clone().reserve_owned().await.unwrap();
+            sender
+                .send(Submission {
+                    transaction,
+                    reply: Some(Reply { permit, line }),
+                    position: None,
+                })
+                .await
+                .unwrap();
+        }
+        drop(sender);
+
+        let db_layers = (0..2).map(|_| HashMapDb::new(2)).collect();
+        let (mut outcomes, handle) =
+            start(receiver, db_layers, Policy::default(), false, source(), 2);
+        while let Some(outcome) = outcomes.recv().await {
+            match outcome {
+                Outcome::Processed {
+                    transaction, reply, ..
+                } => {
+                    let reply = reply.unwrap();
+                    let acknowledgement = Acknowledgement::accepted(reply.line, transaction.tx);
+                    reply.send(acknowledgement);
+                }
+                Outcome::Malformed { error, reply } => {
+                    let reply = reply.unwrap();
+                    let acknowledgement = Acknowledgement::malformed(reply.line, error.to_string());
+                    reply.send(acknowledgement);
+                }
+                Outcome::Skipped => unreachable!("every transaction is read once"),
+            }
+        }
+        handle.await.unwrap();
+        drop(reply);
+
+        let mut actual = Vec::new();
+        while let Some(acknowledgement) = replies.recv().await {
+            actual.push((acknowledgement.status, acknowledgement.line));
+        }
+        let expected = vec![
+            (Status::Accepted, 1),
+            (Status::Malformed, 2),
+            (Status::Accepted, 3),
+        ];
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn strict_mode_stops_after_earlier_transactions() {
+        let malformed = || ReadError {
+            line: 0,
+            record: String::new(),
+            error: "malformed".into(),
+        };
+        let deposit = |tx| Transaction {
+            ty: TransactionType::Deposit,
+            client: tx as u16,
+            tx,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+
+        let (sender, receiver) = mpsc::channel(24);
+        for tx in 0..24 {
+            let transaction = if tx == 16 {
+                Err(malformed())
+            } else {
+                Ok(deposit(tx))
+            };
+            sender
+                .send(Submission {
+                    transaction,
+                    reply: None,
+                    position: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let db_layers = (0..4).map(|_| HashMapDb::new(2)).collect();
+        let (mut outcomes, handle) =
+            start(receiver, db_layers, Policy::default(), true, source(), 2);
+
+        // Every transaction read before the malformed record is processed ahead of it, and
+        // nothing is read after it
+        let mut processed = Vec::new();
+        while let Some(outcome) = outcomes.recv().await {
+            match outcome {
+                Outcome::Processed { transaction, .. } => processed.push(transaction.tx),
+                Outcome::Malformed { .. } => {
+                    processed.sort_unstable();
+                    assert_eq!((0..16).collect::<Vec<_>>(), processed);
+                    assert!(outcomes.recv().await.is_none());
+                    break;
+                }
+                Outcome::Skipped => unreachable!("every transaction is read once"),
+            }
+        }
+        assert!(sender.is_closed());
+        assert_eq!(16, clients(handle.await.unwrap().db_layers).await.len());
+    }
+
+    #[tokio::test]
+    async fn records_refusals_across_workers() {
+        let dir = tempfile::TempDir::new_in("./").unwrap();
+        let db = SledDb::new(dir.path().join("database"), 2).unwrap();
+
+        let deposit = |client| Transaction {
+            ty: TransactionType::Deposit,
+            client,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let (sender, receiver) = mpsc::channel(2);
+        for client in [1, 2] {
+            sender
+                .send(Submission {
+                    transaction: Ok(deposit(client)),
+                    reply: None,
+                    position: None,
+                })
+                .await
+                .unwrap();
+        }
+        drop(sender);
+
+        let (mut outcomes, handle) = start(
+            receiver,
+            db.shards(2, 2),
+            Policy::default(),
+            false,
+            source(),
+            2,
+        );
+        while outcomes.recv().await.is_some() {}
+        let mut db_layers = handle.await.unwrap().db_layers;
+
+        // The refusal is recorded by the worker of client 2 exactly as a sequential run would
+        let history = db_layers[shard_of(2, 2)]
+            .client_history(2, 0..u64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(1, history.len());
+        assert_eq!(Some("DuplicateTransaction"), history[0].error.as_deref());
+        assert_eq!(
+            Some(1),
+            db_layers[shard_of(2, 2)]
+                .cursor("test", 2)
+                .await
+                .unwrap()
+                .map(|cursor| cursor.sequence)
+        );
+        assert_eq!(
+            vec![deposit(1), deposit(2)],
+            db.logged_transactions()
+                .unwrap()
+                .collect::<Result<Vec<_>, Error>>()
+                .unwrap()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn matches_sequential(input in transactions()) {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let expected = runtime.block_on(sequential(&input));
+            let actual = runtime.block_on(sharded(&input, 4));
+            prop_assert_eq!(expected, actual);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{model, reader::ReadError};
+
+/// The exit code used when replaying the transaction log rebuilt clients differing from the stored
+/// ones, the same as `diff` exiting with differences
+pub const EXIT_DIVERGED: i32 = 1;
+
+/// The exit code used when the command line arguments can't be used, the same as clap exits with
+/// for invalid arguments
+pub const EXIT_USAGE: i32 = 2;
+
+/// The exit code used when a record could not be read as a transaction in strict mode
+pub const EXIT_MALFORMED_INPUT: i32 = 65;
+
+/// The exit code used when processing failed for a reason which is a bug rather than a problem
+/// with the environment or with the input
+pub const EXIT_INTERNAL: i32 = 70;
+
+/// The exit code used when a file or standard stream could not be opened, read, or written
+pub const EXIT_IO: i32 = 74;
+
+/// The exit code used when the database could not be opened or failed during the run. Such errors
+/// are often temporary, so a run which failed this way is worth retrying
+pub const EXIT_DATABASE: i32 = 75;
+
+/// Every error which can stop a run of the application. Unlike [`model::Error`], which also
+/// describes transactions which are refused while the run carries on, every `AppError` is fatal
+#[derive(Debug)]
+pub enum AppError {
+    /// Opening, reading, or writing a file or standard stream failed
+    Io(std::io::Error),
+
+    /// Reading or writing CSV data failed
+    Csv(csv_async::Error),
+
+    /// The database could not be opened
+    Database(sled::Error),
+
+    /// A record could not be read as a transaction and the run is in strict mode
+    MalformedInput(ReadError),
+
+    /// The policy flags differ from the ones the database was first processed with, such that its
+    /// transaction log could no longer be replayed
+    PolicyChanged,
+
+    /// Replaying the transaction log rebuilt the given number of clients differently from how they
+    /// are stored
+    Diverged(usize),
+
+    /// The [`crate::db_layer::DbLayer`], a [`crate::writer::ClientWriter`], or a
+    /// [`crate::writer::RejectionWriter`] failed
+    Model(model::Error),
+}
+
+impl AppError {
+    /// Whether the error is down to a single input, such that any other input may still be
+    /// processed
+    pub fn is_input_error(&self) -> bool {
+        matches!(
+            self,
+            AppError::Io(_) | AppError::Csv(_) | AppError::MalformedInput(_)
+        )
+    }
+
+    /// The code the process should exit with because of this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Io(_) | AppError::Csv(_) => EXIT_IO,
+            AppError::Database(_) => EXIT_DATABASE,
+            AppError::MalformedInput(_) => EXIT_MALFORMED_INPUT,
+            AppError::Diverged(_) => EXIT_DIVERGED,
+            AppError::PolicyChanged => EXIT_USAGE,
+            AppError::Model(model::Error::DbLayer(_)) => EXIT_DATABASE,
+            AppError::Model(model::Error::Writer(_)) => EXIT_IO,
+            AppError::Model(_) => EXIT_INTERNAL,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::Csv(e) => write!(f, "CSV error: {}", e),
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            AppError::MalformedInput(e) => write!(f, "{}", e),
+            AppError::PolicyChanged => write!(
+                f,
+                "the policy flags differ from the ones the database was first processed with"
+            ),
+            AppError::Diverged(count) => {
+                write!(f, "{} clients differ from the transaction log", count)
+            }
+            AppError::Model(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> AppError {
+        AppError::Io(e)
+    }
+}
+
+impl From<csv_async::Error> for AppError {
+    fn from(e: csv_async::Error) -> AppError {
+        AppError::Csv(e)
+    }
+}
+
+impl From<sled::Error> for AppError {
+    fn from(e: sled::Error) -> AppError {
+        AppError::Database(e)
+    }
+}
+
+impl From<ReadError> for AppError {
+    fn from(e: ReadError) -> AppError {
+        AppError::MalformedInput(e)
+    }
+}
+
+impl From<model::Error> for AppError {
+    fn from(e: model::Error) -> AppError {
+        AppError::Model(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let not_found = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let malformed = ReadError {
+            line: 2,
+            record: "deposit,x,1,1.0".to_owned(),
+            error: "invalid digit found in string".into(),
+        };
+
+        assert_eq!(EXIT_IO, AppError::from(not_found).exit_code());
+        assert_eq!(EXIT_MALFORMED_INPUT, AppError::from(malformed).exit_code());
+        assert_eq!(
+            EXIT_DATABASE,
+            AppError::from(model::Error::DbLayer("corrupt".to_owned())).exit_code()
+        );
+        assert_eq!(
+            EXIT_IO,
+            AppError::from(model::Error::Writer("closed".to_owned())).exit_code()
+        );
+        assert_eq!(EXIT_USAGE, AppError::PolicyChanged.exit_code());
+        assert_eq!(
+            EXIT_INTERNAL,
+            AppError::from(model::Error::NoAmount).exit_code()
+        );
+    }
+}
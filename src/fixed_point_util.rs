@@ -1,64 +1,253 @@
-use serde::{
-    de::{Error, Visitor},
-    Deserializer, Serializer,
-};
+use serde::Serializer;
+use std::convert::TryFrom;
+
+/// Format an i64 value such that its four least significant digits are behind a decimal point
+pub fn format(value: i64) -> String {
+    // The magnitude is taken as a u64 such that `i64::MIN` does not overflow when negated
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+    let scale = 10u64.pow(DECIMAL_PLACES as u32);
+
+    format!(
+        "{}{}.{:0width$}",
+        sign,
+        magnitude / scale,
+        magnitude % scale,
+        width = DECIMAL_PLACES
+    )
+}
 
 /// Serialize an i64 value such that its four least significant values are behind a decimal point
 pub fn serialize<S: Serializer>(value: &i64, serializer: S) -> Result<S::Ok, S::Error> {
-    let mut value = *value;
-
-    let one = value % 10;
-    value /= 10;
-    let two = value % 10;
-    value /= 10;
-    let three = value % 10;
-    value /= 10;
-    let four = value % 10;
-    value /= 10;
-
-    let mut string = String::new();
-    string.push_str(&value.to_string());
-    string.push('.');
-    string.push_str(&four.to_string());
-    string.push_str(&three.to_string());
-    string.push_str(&two.to_string());
-    string.push_str(&one.to_string());
-
-    serializer.serialize_str(&string)
+    serializer.serialize_str(&format(*value))
+}
+
+/// Serialize an optional i64 value as with [`serialize`] or as an empty value if it is None
+pub fn serialize_option<S: Serializer>(
+    value: &Option<i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize(value, serializer),
+        None => serializer.serialize_none(),
+    }
 }
 
-/// Deserialize a floating point number into an i64 where its four least significant decimal digits
-/// are considered behind a decimal point
-pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
-    struct FixedPoint;
-    impl<'de> Visitor<'de> for FixedPoint {
-        type Value = Option<i64>;
+/// The number of decimal digits kept behind the decimal point
+const DECIMAL_PLACES: usize = 4;
+
+/// The policy applied to amounts which have more than [`DECIMAL_PLACES`] digits behind the decimal
+/// point
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Rounding {
+    /// Refuse the amount unless every extra digit is a zero
+    Reject,
+    /// Round to the nearest representable amount with ties going to the even neighbour
+    HalfEven,
+    /// Discard the extra digits, rounding towards zero
+    Truncate,
+}
 
-        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rounding, String> {
+        match s {
+            "reject" => Ok(Rounding::Reject),
+            "half-even" => Ok(Rounding::HalfEven),
+            "truncate" => Ok(Rounding::Truncate),
+            other => Err(format!(
+                "unknown rounding policy `{}`, expected one of `reject`, `half-even`, `truncate`",
+                other
+            )),
+        }
+    }
+}
+
+/// The ways in which a decimal string can fail to be parsed into a fixed point value
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ParseError {
+    /// The string contains no digits
+    Empty,
+    /// The string contains something other than a sign, digits, and a single decimal point
+    InvalidDigit,
+    /// The string has more than four significant digits behind the decimal point and the
+    /// [`Rounding`] is [`Rounding::Reject`]
+    TooPrecise,
+    /// The value does not fit in an i64 once scaled
+    Overflow,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "decimal value has no digits"),
+            ParseError::InvalidDigit => write!(f, "invalid digit found in decimal value"),
+            ParseError::TooPrecise => write!(
                 f,
-                "decimal value with at most four place values behind the decimal or nothing"
-            )
+                "decimal value has more than {} place values behind the decimal",
+                DECIMAL_PLACES
+            ),
+            ParseError::Overflow => write!(f, "decimal value is too large"),
         }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a decimal string exactly into an i64 where its four least significant decimal digits are
+/// considered behind a decimal point. Any digits past the fourth behind the decimal point are
+/// handled according to the given [`Rounding`]
+pub fn parse(s: &str, rounding: Rounding) -> Result<i64, ParseError> {
+    let (negative, s) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let (integer, fraction) = match s.find('.') {
+        Some(index) => (&s[..index], &s[index + 1..]),
+        None => (s, ""),
+    };
 
-        fn visit_none<E: Error>(self) -> Result<Option<i64>, E> {
-            Ok(None)
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if !integer
+        .bytes()
+        .chain(fraction.bytes())
+        .all(|b| b.is_ascii_digit())
+    {
+        return Err(ParseError::InvalidDigit);
+    }
+
+    // The magnitude is accumulated in an i128 such that `i64::MIN` can be represented before the
+    // sign is applied
+    let mut magnitude: i128 = 0;
+    let kept = fraction.bytes().chain(std::iter::repeat(b'0'));
+    for digit in integer.bytes().chain(kept.take(DECIMAL_PLACES)) {
+        magnitude = magnitude * 10 + i128::from(digit - b'0');
+        if magnitude > i128::from(i64::MAX) + 1 {
+            return Err(ParseError::Overflow);
         }
+    }
 
-        fn visit_some<D: Deserializer<'de>>(
-            self,
-            deserializer: D,
-        ) -> Result<Option<i64>, D::Error> {
-            deserializer.deserialize_f32(FixedPoint)
+    let extra = fraction.get(DECIMAL_PLACES..).unwrap_or("");
+    if extra.bytes().any(|b| b != b'0') {
+        match rounding {
+            Rounding::Reject => return Err(ParseError::TooPrecise),
+            Rounding::Truncate => {}
+            Rounding::HalfEven => {
+                let first = extra.as_bytes()[0];
+                let rest_nonzero = extra[1..].bytes().any(|b| b != b'0');
+                if first > b'5' || (first == b'5' && (rest_nonzero || magnitude % 2 == 1)) {
+                    magnitude += 1;
+                }
+            }
         }
+    }
 
-        fn visit_f32<E: Error>(self, value: f32) -> Result<Option<i64>, E> {
-            let mut fp_num = value * 10_000f32;
-            fp_num = fp_num.round();
+    let value = if negative { -magnitude } else { magnitude };
+    i64::try_from(value).map_err(|_| ParseError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Amount {
+        #[serde(serialize_with = "serialize_option")]
+        amount: Option<i64>,
+    }
 
-            Ok(Some(fp_num as i64))
+    #[test]
+    fn format_values() {
+        assert_eq!("0.0000", format(0));
+        assert_eq!("1.5000", format(15000));
+        assert_eq!("0.0001", format(1));
+        assert_eq!("-1.5000", format(-15000));
+        assert_eq!("-0.5000", format(-5000));
+        assert_eq!("-0.0001", format(-1));
+        assert_eq!("922337203685477.5807", format(i64::MAX));
+        assert_eq!("-922337203685477.5808", format(i64::MIN));
+    }
+
+    proptest! {
+        #[test]
+        fn format_parse_round_trip(value in any::<i64>()) {
+            prop_assert_eq!(Ok(value), parse(&format(value), Rounding::Reject));
+        }
+
+        #[test]
+        fn serialize_parse_round_trip(value in any::<i64>()) {
+            let json = serde_json::to_value(Amount { amount: Some(value) }).unwrap();
+            prop_assert_eq!(Ok(value), parse(json["amount"].as_str().unwrap(), Rounding::Reject));
         }
     }
 
-    deserializer.deserialize_option(FixedPoint)
+    #[test]
+    fn parse_exact() {
+        assert_eq!(Ok(10000), parse("1", Rounding::Reject));
+        assert_eq!(Ok(15000), parse("1.5", Rounding::Reject));
+        assert_eq!(Ok(5000), parse(".5", Rounding::Reject));
+        assert_eq!(Ok(-5000), parse("-0.5", Rounding::Reject));
+        assert_eq!(Ok(10000), parse("+1.", Rounding::Reject));
+        assert_eq!(Ok(1234567891), parse("123456.7891", Rounding::Reject));
+        assert_eq!(Ok(12345), parse("1.234500", Rounding::Reject));
+        assert_eq!(
+            Ok(i64::MAX),
+            parse("922337203685477.5807", Rounding::Reject)
+        );
+        assert_eq!(
+            Ok(i64::MIN),
+            parse("-922337203685477.5808", Rounding::Reject)
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Err(ParseError::Empty), parse("", Rounding::Reject));
+        assert_eq!(Err(ParseError::Empty), parse("-.", Rounding::Reject));
+        assert_eq!(
+            Err(ParseError::InvalidDigit),
+            parse("1.2.3", Rounding::Reject)
+        );
+        assert_eq!(
+            Err(ParseError::InvalidDigit),
+            parse("1e5", Rounding::Reject)
+        );
+        assert_eq!(
+            Err(ParseError::InvalidDigit),
+            parse("--1", Rounding::Reject)
+        );
+        assert_eq!(
+            Err(ParseError::TooPrecise),
+            parse("1.00001", Rounding::Reject)
+        );
+        assert_eq!(
+            Err(ParseError::Overflow),
+            parse("922337203685477.5808", Rounding::Reject)
+        );
+        assert_eq!(
+            Err(ParseError::Overflow),
+            parse("100000000000000000000000", Rounding::Truncate)
+        );
+    }
+
+    #[test]
+    fn parse_rounding() {
+        assert_eq!(Ok(10000), parse("1.00005", Rounding::HalfEven));
+        assert_eq!(Ok(10002), parse("1.00015", Rounding::HalfEven));
+        assert_eq!(Ok(10001), parse("1.000051", Rounding::HalfEven));
+        assert_eq!(Ok(-10001), parse("-1.00006", Rounding::HalfEven));
+        assert_eq!(Ok(10000), parse("1.00009", Rounding::Truncate));
+        assert_eq!(Ok(-10000), parse("-1.00009", Rounding::Truncate));
+        assert_eq!(
+            Err(ParseError::Overflow),
+            parse("922337203685477.58075", Rounding::HalfEven)
+        );
+    }
 }
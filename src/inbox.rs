@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+
+use crate::{
+    cli::Cli, db_layer::sled_db::SledDb, error::AppError, inputs, writer::RejectionWriter, Input,
+};
+
+/// Process every file dropped into the inbox directory as its own batch until `stopped` turns
+/// true, looking for new files every `interval`. Each file is moved into the `processed`
+/// subdirectory of the inbox once done, or into the `failed` one if it can't be read or holds a
+/// malformed record in strict mode, and the rejections and final state of every client are written
+/// after each batch. A file is only read once its size and modification time stayed the same
+/// between two looks, such that files still being written are left alone
+pub async fn watch<R: RejectionWriter>(
+    cli: &Cli,
+    inbox: &Path,
+    interval: Duration,
+    db: &SledDb,
+    mut rejections: Option<R>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    let processed = inbox.join("processed");
+    let failed = inbox.join("failed");
+    tokio::fs::create_dir_all(&processed).await?;
+    tokio::fs::create_dir_all(&failed).await?;
+    eprintln!("Watching {}", inbox.display());
+
+    // The size and modification time of every file in the inbox when it was last looked at
+    let mut seen: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+    'watching: while !*stopped.borrow() {
+        let mut files = inputs::list(inbox).await?;
+        inputs::sort(&mut files, cli.order);
+
+        let mut settled = Vec::new();
+        let mut looked_at = HashMap::new();
+        for file in files {
+            // A file may be taken away again between listing and looking at it
+            let metadata = match tokio::fs::metadata(&file).await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let state = (metadata.len(), metadata.modified()?);
+            if seen.get(&file) == Some(&state) {
+                settled.push(file.clone());
+            }
+            looked_at.insert(file, state);
+        }
+        seen = looked_at;
+
+        for file in settled {
+            if *stopped.borrow() {
+                break 'watching;
+            }
+
+            let mut db_layers = db.shards(cli.workers as usize, cli.db_buffer);
+            let result = crate::run_batch(
+                cli,
+                Input::Dropped(file.clone()),
+                &mut db_layers,
+                rejections.as_mut(),
+                stopped.clone(),
+            )
+            .await;
+
+            // The rejections of every batch are written out with it rather than once watching stops
+            if let Some(rejections) = rejections.as_mut() {
+                rejections.flush().await?;
+            }
+
+            // A file whose reading was stopped is left in the inbox such that the next run resumes it
+            let destination = match result {
+                Err(e) if !e.is_input_error() => return Err(e),
+                _ if *stopped.borrow() => break 'watching,
+                Ok(()) => &processed,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", file.display(), e);
+                    &failed
+                }
+            };
+            if let Err(e) = move_into(&file, destination).await {
+                eprintln!("Failed to move {}: {}", file.display(), e);
+            }
+
+            crate::write_output(cli, db_layers).await?;
+        }
+
+        // A closed channel means no signal can ever arrive, so only the interval is waited for
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            Ok(()) = stopped.changed() => {}
+        }
+    }
+
+    if let Some(rejections) = rejections {
+        rejections.close().await?;
+    }
+    Ok(())
+}
+
+/// Move a file into a directory under the same name, followed by `.1`, `.2`, and so on if the
+/// directory already holds a file of that name
+async fn move_into(file: &Path, directory: &Path) -> std::io::Result<()> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let mut destination = directory.join(&*name);
+    let mut copy = 0;
+    while tokio::fs::metadata(&destination).await.is_ok() {
+        copy += 1;
+        destination = directory.join(format!("{}.{}", name, copy));
+    }
+    tokio::fs::rename(file, destination).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use crate::writer::csv::CsvRejectionWriter;
+
+    /// Wait until a file exists, giving up after a few seconds
+    async fn wait_for(file: &Path) {
+        for _ in 0..500 {
+            if file.exists() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never appeared", file.display());
+    }
+
+    #[tokio::test]
+    async fn process_dropped_files() {
+        let dir = TempDir::new_in("./").unwrap();
+        let inbox = dir.path().join("inbox");
+        let output = dir.path().join("clients.csv");
+        let rejections = dir.path().join("rejections.csv");
+        tokio::fs::create_dir(&inbox).await.unwrap();
+        tokio::fs::write(inbox.join("a.csv"), "deposit,1,1,1.5\ndeposit,2,2,2.0\n")
+            .await
+            .unwrap();
+
+        let cli = Cli::parse_from([
+            "transaction_processor".as_ref(),
+            "--strict".as_ref(),
+            "--output".as_ref(),
+            output.as_os_str(),
+        ]);
+        let db = SledDb::new(dir.path().join("database"), 2).unwrap();
+        let (stop, stopped) = watch::channel(false);
+
+        let dropping = async {
+            wait_for(&inbox.join("processed").join("a.csv")).await;
+            wait_for(&output).await;
+            assert_eq!(
+                "client,available,held,total,locked\n\
+                 1,1.5000,0.0000,1.5000,false\n\
+                 2,2.0000,0.0000,2.0000,false\n",
+                tokio::fs::read_to_string(&output).await.unwrap()
+            );
+
+            // A file dropped later under the same name is read all the same
+            tokio::fs::write(inbox.join("a.csv"), "withdrawal,1,3,0.5\nbogus\n")
+                .await
+                .unwrap();
+            wait_for(&inbox.join("failed").join("a.csv")).await;
+            tokio::fs::write(inbox.join("b.csv"), "deposit,2,4,1.0\nwithdrawal,1,5,9.0\n")
+                .await
+                .unwrap();
+            wait_for(&inbox.join("processed").join("b.csv")).await;
+
+            // The rejections of a file are written as soon as it has been processed
+            assert_eq!(
+                "type,client,tx,amount,error,reason\n\
+                 withdrawal,1,5,9.0000,InsufficientFunds,\
+                 the withdrawal exceeds the available funds of the client\n",
+                tokio::fs::read_to_string(&rejections).await.unwrap()
+            );
+            stop.send(true).unwrap();
+        };
+
+        let (result, ()) = tokio::join!(
+            watch(
+                &cli,
+                &inbox,
+                Duration::from_millis(10),
+                &db,
+                Some(CsvRejectionWriter::append(&rejections).await.unwrap()),
+                stopped
+            ),
+            dropping
+        );
+        result.unwrap();
+
+        assert_eq!(
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n",
+            tokio::fs::read_to_string(&output).await.unwrap()
+        );
+        assert!(inputs::list(&inbox).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use crate::cli::InputOrder;
+
+/// Expand the input paths given on the command line into the files to read, in the order to read
+/// them. A file is read as given, even if it doesn't exist such that failing to open it is
+/// reported. A directory stands for every file directly inside it and a path which doesn't exist
+/// but holds any of `*`, `?`, or `[` for every file matching it as a glob pattern. The files of a
+/// directory or pattern are read in the given order, hidden files left out
+pub async fn expand(paths: &[PathBuf], order: InputOrder) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        let mut found = if tokio::fs::metadata(path)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            list(path).await?
+        } else if !path.exists() && is_pattern(path) {
+            matching(path)?
+        } else {
+            files.push(path.clone());
+            continue;
+        };
+
+        if found.is_empty() {
+            eprintln!("No files found in {}", path.display());
+        }
+        sort(&mut found, order);
+        files.append(&mut found);
+    }
+    Ok(files)
+}
+
+/// Every file directly inside a directory which isn't hidden
+pub async fn list(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() && !is_hidden(&entry.path()) {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Sort files by name or by the timestamp embedded in their names. The timestamp of a file is
+/// every digit of its name taken together, such as `20241018` for `tx-2024-10-18.csv`, and files
+/// with the same timestamp are sorted by name
+pub fn sort(files: &mut [PathBuf], order: InputOrder) {
+    match order {
+        InputOrder::Name => files.sort_by_key(|file| name(file)),
+        InputOrder::Timestamp => files.sort_by_key(|file| {
+            let name = name(file);
+            let digits = name
+                .chars()
+                .filter(char::is_ascii_digit)
+                .skip_while(|&digit| digit == '0')
+                .collect::<String>();
+            (digits.len(), digits, name)
+        }),
+    }
+}
+
+fn name(file: &Path) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn is_hidden(file: &Path) -> bool {
+    name(file).starts_with('.')
+}
+
+fn is_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['].as_ref())
+}
+
+/// Every file matching a glob pattern which isn't hidden
+fn matching(pattern: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let invalid = |e: glob::PatternError| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let mut files = Vec::new();
+    for entry in glob::glob(&pattern.to_string_lossy()).map_err(invalid)? {
+        let file = entry.map_err(std::io::Error::from)?;
+        if file.is_file() && !is_hidden(&file) {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn expand_directories_and_patterns() {
+        let dir = TempDir::new_in("./").unwrap();
+        for name in [
+            "tx-2024-10-18.csv",
+            "tx-2024-9-30.csv",
+            "a-2024-10-02.csv",
+            ".tx-2024-10-01.csv",
+            "notes.txt",
+        ] {
+            tokio::fs::write(dir.path().join(name), "").await.unwrap();
+        }
+        tokio::fs::create_dir(dir.path().join("processed"))
+            .await
+            .unwrap();
+
+        let names = |files: Vec<PathBuf>| files.iter().map(|file| name(file)).collect::<Vec<_>>();
+
+        let files = expand(&[dir.path().into()], InputOrder::Name)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                "a-2024-10-02.csv",
+                "notes.txt",
+                "tx-2024-10-18.csv",
+                "tx-2024-9-30.csv"
+            ],
+            names(files)
+        );
+
+        let files = expand(&[dir.path().join("*.csv")], InputOrder::Timestamp)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["tx-2024-9-30.csv", "a-2024-10-02.csv", "tx-2024-10-18.csv"],
+            names(files)
+        );
+
+        // Files are read in the order given, whether they exist or not
+        let given = vec![dir.path().join("notes.txt"), dir.path().join("missing.csv")];
+        assert_eq!(given, expand(&given, InputOrder::Name).await.unwrap());
+    }
+}
@@ -1,66 +1,712 @@
+mod cli;
 mod db_layer;
+mod dispatcher;
+mod error;
 mod fixed_point_util;
+mod inbox;
+mod inputs;
 mod model;
 mod reader;
+mod replay;
 mod transaction_processing;
 mod writer;
 
-use db_layer::DbLayer;
+use clap::Parser;
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc::Receiver};
+
+use cli::{Backend, Cli, Command, Format, OutputFormat};
+use db_layer::{ClientOrder, DbLayer};
+use error::AppError;
 use model::*;
-use reader::TransactionReader;
-use writer::ClientWriter;
+use reader::{Reply, Shutdown, Submission, TransactionReader};
+use writer::{ClientWriter, RejectionWriter};
 
-/// The number of [`Transaction`]s to allow in the [`tokio::sync::mpsc::Receiver`]'s queue. Each
-/// [`Transaction`] will be roughly 120 bytes (plus padding) and the overhead of the mpsc channel.
-// TODO: Make this number configurable
-const READER_BUFFER: usize = 1024;
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
 
-/// The number of [`Client`]s to allow in the [`tokio::sync::mpsc::Receiver`]'s queue. Each
-/// [`Client`] will be roughly 200 bytes (plus padding) and the overhead of the mpsc channel.
-// TODO: Make this number configurable
-const DB_BUFFER: usize = 1024;
+    // Any error ends the run with a message and an exit code distinct to its kind rather than a
+    // panic such that whatever scheduled the run can decide whether to retry it
+    let code = match try_main(cli).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            e.exit_code()
+        }
+    };
 
-/// The path of the RocksDB key value store
-// TODO: Make this path configurable
-const DB_PATH: &str = "./database";
+    // Exit rather than return as the runtime would otherwise wait for a read of stdin which was
+    // abandoned on shutdown to complete
+    std::process::exit(code);
+}
 
-// FIXME: Eliminate unwraps
-#[tokio::main]
-async fn main() {
-    let mut args = std::env::args();
-    let _ = args.next();
-
-    // Read from a CSV file with the path given in the first argument
-    let reader = reader::csv::CsvReader::new(
-        args.next()
-            .expect("Must have one argument with the path of a CSV file"),
-        READER_BUFFER,
-    )
-    .await
-    .unwrap();
-    let mut receiver = reader.start();
+async fn try_main(cli: Cli) -> Result<(), AppError> {
+    match cli.command {
+        Some(Command::History { client, from, to }) => {
+            return history(&cli, client, from..to).await
+        }
+        Some(Command::Balance { client, batch }) => return balance(&cli, client, batch).await,
+        Some(Command::Replay { verify }) => return replay(&cli, verify).await,
+        Some(Command::Watch {
+            ref inbox,
+            interval,
+        }) => return watch(&cli, inbox, interval).await,
+        None => {}
+    }
+
+    // Using a couple of `HashMaps` or a `sled::Db`, hold transaction and client information with
+    // one `DbLayer` per worker
+    match cli.backend {
+        Backend::Memory => {
+            let db_layers = (0..cli.workers as usize)
+                .map(|_| db_layer::hashmap::HashMapDb::new(cli.db_buffer))
+                .collect();
+            ingest(&cli, db_layers).await
+        }
+        Backend::Sled => {
+            let db_layers = open_for_processing(&cli)?.shards(cli.workers as usize, cli.db_buffer);
+            ingest(&cli, db_layers).await
+        }
+    }
+}
+
+/// Process every input given on the command line as its own batch with the given [`DbLayer`]s and
+/// write the final state of each [`Client`]
+async fn ingest<D: DbLayer + Send + 'static>(cli: &Cli, db_layers: Vec<D>) -> Result<(), AppError> {
+    // Every rejected transaction is reported to the given path as JSON-lines if it has a `.json`
+    // or `.jsonl` extension or as CSV otherwise
+    match &cli.rejections {
+        Some(path) if is_json(path) => {
+            let rejections = writer::json::JsonLinesRejectionWriter::new(path).await?;
+            ingest_batches(cli, db_layers, Some(rejections)).await
+        }
+        Some(path) => {
+            let rejections = writer::csv::CsvRejectionWriter::new(path).await?;
+            ingest_batches(cli, db_layers, Some(rejections)).await
+        }
+        None => ingest_batches::<_, writer::csv::CsvRejectionWriter>(cli, db_layers, None).await,
+    }
+}
 
-    // Using a couple of `HashMaps` or a `sled::Db`, hold transaction and client information
-    #[cfg(feature = "no_persist")]
-    let mut db_layer = db_layer::hashmap::HashMapDb::new(DB_BUFFER);
-    #[cfg(not(feature = "no_persist"))]
-    let mut db_layer = db_layer::sled_db::SledDb::new(DB_PATH, DB_BUFFER).unwrap();
+/// Where the transactions of a single batch are read from
+enum Input {
+    Listen(String),
+    File(PathBuf),
+
+    /// A file dropped into the inbox of [`watch`], which is only ever read once under its path.
+    /// Its identity includes when it was last modified, such that another file dropped later under
+    /// the same name is never taken for it
+    Dropped(PathBuf),
+
+    Stdin,
+}
+
+impl std::fmt::Display for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Input::Listen(addr) => write!(f, "{}", addr),
+            Input::File(path) | Input::Dropped(path) => write!(f, "{}", path.display()),
+            Input::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+async fn ingest_batches<D: DbLayer + Send + 'static, R: RejectionWriter>(
+    cli: &Cli,
+    mut db_layers: Vec<D>,
+    mut rejections: Option<R>,
+) -> Result<(), AppError> {
+    let stopped = stop_on_signal();
+
+    // Read from TCP connections if an address to listen on is given, otherwise read from every
+    // CSV or JSON-lines file given, or from stdin if none is given
+    let inputs = match &cli.listen {
+        Some(addr) => vec![Input::Listen(addr.clone())],
+        None if cli.input.is_empty() => vec![Input::Stdin],
+        None => inputs::expand(&cli.input, cli.order)
+            .await?
+            .into_iter()
+            .map(Input::File)
+            .collect(),
+    };
+
+    // With several inputs one which fails is reported and skipped, and the run fails once every
+    // other input has been processed and the clients have been written. In strict mode a single
+    // input stops the run on the first malformed record without outputting any clients
+    let several = inputs.len() > 1;
+    let mut failure = None;
+    let mut result = Ok(());
+    for input in inputs {
+        if *stopped.borrow() {
+            break;
+        }
+
+        let name = input.to_string();
+        match run_batch(
+            cli,
+            input,
+            &mut db_layers,
+            rejections.as_mut(),
+            stopped.clone(),
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(e) if several && e.is_input_error() => {
+                eprintln!("Skipping {}: {}", name, e);
+                failure.get_or_insert(e);
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    // The rejections recorded before a run stops early are kept as well
+    let closed = match rejections {
+        Some(rejections) => rejections.close().await,
+        None => Ok(()),
+    };
+    result?;
+    closed?;
+
+    write_output(cli, db_layers).await?;
+    failure.map_or(Ok(()), Err)
+}
 
-    // Process each transaction from the reader's receiver
-    while let Some(input) = receiver.recv().await {
-        // Fail silently here
-        let _ = transaction_processing::process_transaction(&mut db_layer, input).await;
+/// Start reading a single input, resuming a CSV file read before from its [`ResumePoint`]. Sequence
+/// numbers otherwise carry on from the highest one stored
+async fn start_reader(
+    cli: &Cli,
+    input: &Input,
+    db_layers: &mut [impl DbLayer],
+) -> Result<(Receiver<Submission>, Shutdown, dispatcher::Source), AppError> {
+    let mut first_sequence = 0;
+    for db_layer in db_layers.iter_mut() {
+        if let Some(last) = db_layer.last_sequence().await? {
+            first_sequence = first_sequence.max(last + 1);
+        }
     }
 
-    // When all transactions in the batch have been processed, write the final state of each Client
-    // to stdout
-    let mut receiver = db_layer.stream_clients().await;
+    let (receiver, shutdown, identity) = match input {
+        Input::Listen(addr) => {
+            let reader =
+                reader::tcp::TcpReader::bind(addr, cli.reader_buffer, cli.rounding).await?;
+            let addr = reader.local_addr()?;
+            eprintln!("Listening on {}", addr);
+            let (receiver, shutdown) = reader.start();
+            (receiver, shutdown, format!("tcp://{}", addr))
+        }
+        Input::File(path) | Input::Dropped(path) => {
+            let mut identity = tokio::fs::canonicalize(path).await?.display().to_string();
+            if let Input::Dropped(_) = input {
+                let modified = tokio::fs::metadata(path).await?.modified()?;
+                let nanos = modified
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|duration| duration.as_nanos())
+                    .unwrap_or_default();
+                identity = format!("{}@{}", identity, nanos);
+            }
+            let (receiver, shutdown) = match cli.input_format(path) {
+                Format::Csv => match resume_point(db_layers, &identity, path).await? {
+                    Some(point) => {
+                        eprintln!(
+                            "Resuming {} from byte {}",
+                            path.display(),
+                            point.position.offset
+                        );
+                        first_sequence = point.sequence;
+                        reader::csv::CsvReader::resume(
+                            path,
+                            point.position,
+                            cli.reader_buffer,
+                            cli.rounding,
+                        )
+                        .await?
+                        .start()
+                    }
+                    None => {
+                        // The numbering is stored before anything is read such that a run stopping
+                        // partway numbers the records the same when they are read again
+                        let point = ResumePoint {
+                            sequence: first_sequence,
+                            position: Position::START,
+                        };
+                        set_resume_point(db_layers, &identity, point).await?;
+                        reader::csv::CsvReader::new(path, cli.reader_buffer, cli.rounding)
+                            .await?
+                            .start()
+                    }
+                },
+                Format::Json => {
+                    reader::json::JsonReader::new(path, cli.reader_buffer, cli.rounding)
+                        .await?
+                        .start()
+                }
+            };
+            (receiver, shutdown, identity)
+        }
+        Input::Stdin => {
+            let (receiver, shutdown) = match cli.input_format.unwrap_or(Format::Csv) {
+                Format::Csv => reader::csv::CsvReader::from_reader(
+                    tokio::io::stdin(),
+                    cli.reader_buffer,
+                    cli.rounding,
+                )
+                .start(),
+                Format::Json => reader::json::JsonReader::from_reader(
+                    tokio::io::stdin(),
+                    cli.reader_buffer,
+                    cli.rounding,
+                )
+                .start(),
+            };
+            (receiver, shutdown, "stdin".to_owned())
+        }
+    };
+
+    let source = dispatcher::Source {
+        identity,
+        first_sequence,
+    };
+    Ok((receiver, shutdown, source))
+}
+
+/// The [`ResumePoint`] of the CSV file at the given path, unless the file no longer holds the records
+/// it was read with, in which case it is read from the start as new input. Every [`DbLayer`] stores
+/// each resume point, so any of them knows it
+async fn resume_point(
+    db_layers: &mut [impl DbLayer],
+    source: &str,
+    path: &Path,
+) -> Result<Option<ResumePoint>, AppError> {
+    let point = match db_layers.first_mut() {
+        Some(db_layer) => db_layer.resume_point(source).await?,
+        None => None,
+    };
+
+    match point {
+        Some(point) if reader::csv::verify(path, point.position).await? => Ok(Some(point)),
+        Some(_) => {
+            eprintln!(
+                "{} changed since it was read, reading it from the start",
+                path.display()
+            );
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Store the [`ResumePoint`] of the given source with every [`DbLayer`]
+async fn set_resume_point(
+    db_layers: &mut [impl DbLayer],
+    source: &str,
+    point: ResumePoint,
+) -> Result<(), AppError> {
+    for db_layer in db_layers.iter_mut() {
+        db_layer.set_resume_point(source, point).await?;
+    }
+    Ok(())
+}
+
+/// Process every file dropped into the inbox against the sled database until SIGINT or SIGTERM is
+/// received
+async fn watch(cli: &Cli, inbox: &Path, interval: u64) -> Result<(), AppError> {
+    let db = open_for_processing(cli)?;
+    let interval = std::time::Duration::from_millis(interval);
+    let stopped = stop_on_signal();
+
+    match &cli.rejections {
+        Some(path) if is_json(path) => {
+            let rejections = writer::json::JsonLinesRejectionWriter::append(path).await?;
+            inbox::watch(cli, inbox, interval, &db, Some(rejections), stopped).await
+        }
+        Some(path) => {
+            let rejections = writer::csv::CsvRejectionWriter::append(path).await?;
+            inbox::watch(cli, inbox, interval, &db, Some(rejections), stopped).await
+        }
+        None => {
+            inbox::watch::<writer::csv::CsvRejectionWriter>(
+                cli, inbox, interval, &db, None, stopped,
+            )
+            .await
+        }
+    }
+}
+
+/// Open the sled database to process transactions against it. The policy flags are stored the first
+/// time, and any later run with other policy flags is refused such that replaying the transaction
+/// log with the stored policy rebuilds the clients exactly
+fn open_for_processing(cli: &Cli) -> Result<db_layer::sled_db::SledDb, AppError> {
+    let db = db_layer::sled_db::SledDb::new(&cli.db_path, cli.db_buffer)?;
+    if db.claim_policy(&cli.policy())? != cli.policy() {
+        return Err(AppError::PolicyChanged);
+    }
+    Ok(db)
+}
+
+/// Write the audit log of a client in the sled database to stdout as JSON-lines
+async fn history(cli: &Cli, client: u16, timestamps: Range<u64>) -> Result<(), AppError> {
+    let mut db_layer = db_layer::sled_db::SledDb::new(&cli.db_path, cli.db_buffer)?;
+
+    let mut stdout = tokio::io::stdout();
+    for entry in db_layer.client_history(client, timestamps).await? {
+        let mut line =
+            serde_json::to_vec(&HumanReadableAuditEntry::from(entry)).map_err(Error::from)?;
+        line.push(b'\n');
+        stdout.write_all(&line).await?;
+    }
+
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// Write a client in the sled database as it was at the end of the given batch to stdout as CSV,
+/// writing nothing if the client had no applied transactions by then
+async fn balance(cli: &Cli, client: u16, batch: u64) -> Result<(), AppError> {
+    let mut db_layer = db_layer::sled_db::SledDb::new(&cli.db_path, cli.db_buffer)?;
+
     let mut writer = writer::csv::CsvWriter::new();
-    while let Some(output) = receiver.recv().await {
-        writer.append_client(output.unwrap()).await.unwrap();
+    if let Some(client) = db_layer.client_as_of(client, batch).await? {
+        writer.append_client(client).await?;
     }
+    writer.close().await?;
+    Ok(())
+}
 
-    // And you'd close the ClientWriter here were it any implementation where thismethod does
-    // something
-    // writer.close().await.unwrap();
+/// Rebuild every client in the sled database from its transaction log with the policy stored in the
+/// database and write them to stdout as CSV, or, when verifying, write every client stored
+/// differently to stdout as JSON-lines
+async fn replay(cli: &Cli, verify: bool) -> Result<(), AppError> {
+    let db_layer = db_layer::sled_db::SledDb::new(&cli.db_path, cli.db_buffer)?;
+
+    // A database which has never been processed has nothing logged, so any policy will do
+    let policy = db_layer.policy()?.unwrap_or_default();
+    let replayed = replay::rebuild(db_layer.logged_transactions()?, &policy, cli.db_buffer).await?;
+
+    if !verify {
+        return write_clients(writer::csv::CsvWriter::new(), vec![replayed], cli.sort).await;
+    }
+
+    let differences = replay::differences(db_layer, replayed).await?;
+    let mut stdout = tokio::io::stdout();
+    for difference in differences.iter() {
+        let difference = replay::HumanReadableDifference::from(difference.clone());
+        let mut line = serde_json::to_vec(&difference).map_err(Error::from)?;
+        line.push(b'\n');
+        stdout.write_all(&line).await?;
+    }
+    stdout.flush().await?;
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::Diverged(differences.len()))
+    }
+}
+
+/// Turn the returned receiver true on SIGINT or SIGTERM, upon which reading stops, but every
+/// transaction already read is processed and the final state of every client is written as if the
+/// input had ended
+fn stop_on_signal() -> tokio::sync::watch::Receiver<bool> {
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(()) => {
+                eprintln!("Shutting down");
+                let _ = stop.send(true);
+            }
+            Err(e) => eprintln!("Failed to listen for signals: {}", e),
+        }
+    });
+    stopped
+}
+
+/// Wait for SIGINT or, on Unix, SIGTERM
+async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// What became of the transactions of a single batch
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+struct Summary {
+    applied: u64,
+    refused: u64,
+    malformed: u64,
+
+    /// Transactions processed by an earlier run reading the same source
+    skipped: u64,
+}
+
+/// Read a single input and process every transaction of it with one worker per given [`DbLayer`],
+/// recording the batch and reporting a summary of it once every transaction has been processed.
+/// Reading stops early once `stopped` turns true, after which a file is resumed from where it
+/// stopped. An input which fails partway is recorded and reported as a batch all the same, as
+/// whatever was processed before the failure stays applied
+async fn run_batch<D: DbLayer + Send + 'static, R: RejectionWriter>(
+    cli: &Cli,
+    input: Input,
+    db_layers: &mut Vec<D>,
+    rejections: Option<&mut R>,
+    mut stopped: tokio::sync::watch::Receiver<bool>,
+) -> Result<(), AppError> {
+    let (receiver, shutdown, source) = start_reader(cli, &input, db_layers).await?;
+    let identity = source.identity.clone();
+    let stop = tokio::spawn(async move {
+        while !*stopped.borrow() {
+            if stopped.changed().await.is_err() {
+                return;
+            }
+        }
+        shutdown.shutdown();
+    });
+
+    let (outcomes, workers) = dispatcher::start(
+        receiver,
+        std::mem::take(db_layers),
+        cli.policy(),
+        cli.strict,
+        source,
+        cli.reader_buffer,
+    );
+    let mut summary = Summary::default();
+    let result = process(outcomes, rejections, cli, &mut summary).await;
+
+    // The workers stop once nothing is listening for outcomes anymore, even if processing failed.
+    // A panic of a worker is a bug and is carried on as such
+    let finished = workers
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    *db_layers = finished.db_layers;
+    stop.abort();
+
+    // Only once every outcome was handled is every record read known to be processed
+    if let (Ok(()), Some(point)) = (&result, finished.reached) {
+        set_resume_point(db_layers, &identity, point).await?;
+    }
+
+    // Once the transactions of the batch have been processed, record the batch such that the
+    // clients can later be looked up as of its end. A failure of processing is reported over any
+    // failure to record what it left behind
+    let batch = match record_batch(db_layers).await {
+        Ok(batch) => batch,
+        Err(e) => return result.and(Err(e)),
+    };
+    eprintln!(
+        "Batch {} ({}): {} applied, {} refused, {} malformed, {} skipped",
+        batch, input, summary.applied, summary.refused, summary.malformed, summary.skipped
+    );
+    result
+}
+
+/// Write the final state of each Client to the output file or to stdout
+async fn write_output(cli: &Cli, db_layers: Vec<impl DbLayer>) -> Result<(), AppError> {
+    match (cli.output_format(), &cli.output) {
+        (OutputFormat::Csv, Some(path)) => {
+            write_clients(
+                writer::csv::CsvWriter::create(path).await?,
+                db_layers,
+                cli.sort,
+            )
+            .await
+        }
+        (OutputFormat::Csv, None) => {
+            write_clients(writer::csv::CsvWriter::new(), db_layers, cli.sort).await
+        }
+        (format, Some(path)) => {
+            let array = format == OutputFormat::Json;
+            write_clients(
+                writer::json::JsonWriter::create(path, array).await?,
+                db_layers,
+                cli.sort,
+            )
+            .await
+        }
+        (format, None) => {
+            let array = format == OutputFormat::Json;
+            write_clients(writer::json::JsonWriter::new(array), db_layers, cli.sort).await
+        }
+    }
+}
+
+/// Snapshot every client of the given [`DbLayer`]s as of a new batch following the last one
+/// recorded, returning the number of the new batch
+async fn record_batch(db_layers: &mut [impl DbLayer]) -> Result<u64, AppError> {
+    // Every `DbLayer` records each batch, so any of them knows the last one
+    let batch = match db_layers.first_mut() {
+        Some(db_layer) => db_layer.last_batch().await?.map_or(0, |batch| batch + 1),
+        None => 0,
+    };
+    for db_layer in db_layers.iter_mut() {
+        db_layer.snapshot(batch).await?;
+    }
+    Ok(batch)
+}
+
+/// Write the final state of every client of the given [`DbLayer`]s with the [`ClientWriter`] in
+/// the given order
+async fn write_clients(
+    mut writer: impl ClientWriter,
+    db_layers: Vec<impl DbLayer>,
+    order: ClientOrder,
+) -> Result<(), AppError> {
+    let mut receivers = Vec::with_capacity(db_layers.len());
+    for db_layer in db_layers {
+        receivers.push(db_layer.stream_clients(order).await);
+    }
+
+    // Every `DbLayer` streams its own clients in order, so the clients of all of them are merged by
+    // always writing whichever next client of each stream comes first
+    let mut next = Vec::with_capacity(receivers.len());
+    for receiver in receivers.iter_mut() {
+        next.push(receiver.recv().await.transpose()?);
+    }
+    while let Some(first) = next
+        .iter()
+        .enumerate()
+        .filter_map(|(i, client)| client.as_ref().map(|client| (i, client)))
+        .min_by(|(_, a), (_, b)| order.compare(a, b))
+        .map(|(i, _)| i)
+    {
+        if let Some(client) = next[first].take() {
+            writer.append_client(client).await?;
+        }
+        next[first] = receivers[first].recv().await.transpose()?;
+    }
+
+    writer.close().await?;
+    Ok(())
+}
+
+/// Whether a file holds JSON-lines rather than CSV judging by its extension
+fn is_json(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("json") | Some("jsonl")
+    )
+}
+
+/// Handle the [`dispatcher::Outcome`] of each transaction, counting it in the [`Summary`], recording
+/// any rejected transaction to the given [`RejectionWriter`] and acknowledging every transaction to
+/// its source if it asked for it. Malformed records are skipped with a warning unless
+/// [`Cli::strict`] is set, in which case the first one is returned. Transactions refused by
+/// [`transaction_processing::process_transaction`] never stop the run, but a failing `DbLayer` or
+/// `RejectionWriter` does
+async fn process<R: RejectionWriter>(
+    mut outcomes: Receiver<dispatcher::Outcome>,
+    mut rejections: Option<&mut R>,
+    cli: &Cli,
+    summary: &mut Summary,
+) -> Result<(), AppError> {
+    while let Some(outcome) = outcomes.recv().await {
+        let (input, result, reply) = match outcome {
+            dispatcher::Outcome::Processed {
+                transaction,
+                result,
+                reply,
+            } => (transaction, result, reply),
+            dispatcher::Outcome::Malformed { error, reply } => {
+                acknowledge(reply, |line| {
+                    Acknowledgement::malformed(line, error.to_string())
+                });
+                summary.malformed += 1;
+                if cli.strict {
+                    return Err(error.into());
+                }
+                eprintln!("Skipping {}", error);
+                continue;
+            }
+
+            dispatcher::Outcome::Skipped => {
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        acknowledge(reply, |line| match &result {
+            Ok(()) => Acknowledgement::accepted(line, input.tx),
+            Err(e) => Acknowledgement::rejected(line, input.tx, e),
+        });
+
+        match result {
+            Ok(()) => summary.applied += 1,
+            Err(e @ Error::DbLayer(_)) => return Err(e.into()),
+            Err(e) => {
+                summary.refused += 1;
+                if let Some(rejections) = rejections.as_mut() {
+                    rejections
+                        .append_rejection(Rejection::new(input, &e))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send an [`Acknowledgement`] back to the source of a transaction if it asked for one, built from
+/// the line of the source on which the record begins
+fn acknowledge(reply: Option<Reply>, acknowledgement: impl FnOnce(u64) -> Acknowledgement) {
+    if let Some(reply) = reply {
+        let line = reply.line;
+        reply.send(acknowledgement(line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn rejections_kept_in_strict_mode() {
+        let dir = TempDir::new_in("./").unwrap();
+        let input = dir.path().join("input.csv");
+        let rejections = dir.path().join("rejections.csv");
+        tokio::fs::write(&input, "deposit,1,1,1.0\nwithdrawal,1,2,5.0\nbogus\n")
+            .await
+            .unwrap();
+
+        let cli = Cli::parse_from([
+            "transaction_processor".as_ref(),
+            "--strict".as_ref(),
+            "--rejections".as_ref(),
+            rejections.as_os_str(),
+            input.as_os_str(),
+        ]);
+        let db_layers = vec![db_layer::hashmap::HashMapDb::new(2)];
+        assert!(matches!(
+            ingest(&cli, db_layers).await,
+            Err(AppError::MalformedInput(_))
+        ));
+
+        // The refusal read before the malformed record is still written
+        let written = tokio::fs::read_to_string(&rejections).await.unwrap();
+        assert_eq!(2, written.lines().count());
+        assert!(written
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("InsufficientFunds"));
+    }
 }
@@ -7,6 +7,8 @@ use crate::fixed_point_util;
 pub enum Error {
     /// If a Deposit or Withdrawal transaction has no amount
     NoAmount,
+    /// If a Deposit or Withdrawal has an amount of zero or less
+    NonPositiveAmount,
     /// If the Withdrawal can not process because of insufficient available funds
     InsufficientFunds,
     /// If the Dispute, Resolve, or Chargeback Transaction can not process because the referenced
@@ -17,11 +19,102 @@ pub enum Error {
     ReferencesWrongClient,
     /// If a Resolve or a Chargeback references a transaction that isn't disputed
     NotDisputed,
+    /// If a Dispute references a transaction which is already disputed
+    AlreadyDisputed,
+    /// If a Dispute references a transaction whose previous dispute was resolved and disputing
+    /// resolved transactions again is not allowed
+    AlreadyResolved,
+    /// If a Dispute references a transaction which has been charged back
+    AlreadyChargedBack,
+    /// If a Dispute references a Withdrawal and disputing withdrawals is not allowed
+    WithdrawalNotDisputable,
+    /// If a transaction is refused because the client's account has been locked after a chargeback
+    AccountLocked,
+    /// If a Deposit or Withdrawal reuses the ID of a transaction which has already been processed
+    DuplicateTransaction,
+    /// If applying a transaction would take a balance of its client past what an i64 can hold
+    Overflow,
 
     /// An error in the DbLayer
     DbLayer(String),
+    /// An error in a ClientWriter or RejectionWriter
+    Writer(String),
 }
 
+impl Error {
+    /// The name of the variant, used to categorize rejected transactions in reports
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Error::NoAmount => "NoAmount",
+            Error::NonPositiveAmount => "NonPositiveAmount",
+            Error::InsufficientFunds => "InsufficientFunds",
+            Error::ReferenceDoesNotExist => "ReferenceDoesNotExist",
+            Error::ReferencesWrongClient => "ReferencesWrongClient",
+            Error::NotDisputed => "NotDisputed",
+            Error::AlreadyDisputed => "AlreadyDisputed",
+            Error::AlreadyResolved => "AlreadyResolved",
+            Error::AlreadyChargedBack => "AlreadyChargedBack",
+            Error::WithdrawalNotDisputable => "WithdrawalNotDisputable",
+            Error::AccountLocked => "AccountLocked",
+            Error::DuplicateTransaction => "DuplicateTransaction",
+            Error::Overflow => "Overflow",
+            Error::DbLayer(_) => "DbLayer",
+            Error::Writer(_) => "Writer",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::NoAmount => write!(f, "deposits and withdrawals must have an amount"),
+            Error::NonPositiveAmount => {
+                write!(f, "deposits and withdrawals must have an amount above zero")
+            }
+            Error::InsufficientFunds => write!(
+                f,
+                "the withdrawal exceeds the available funds of the client"
+            ),
+            Error::ReferenceDoesNotExist => {
+                write!(f, "the referenced transaction does not exist")
+            }
+            Error::ReferencesWrongClient => write!(
+                f,
+                "the referenced transaction belongs to a different client"
+            ),
+            Error::NotDisputed => write!(f, "the referenced transaction is not disputed"),
+            Error::AlreadyDisputed => {
+                write!(f, "the referenced transaction is already disputed")
+            }
+            Error::AlreadyResolved => write!(
+                f,
+                "the referenced transaction has already had a dispute resolved"
+            ),
+            Error::AlreadyChargedBack => {
+                write!(f, "the referenced transaction has been charged back")
+            }
+            Error::WithdrawalNotDisputable => {
+                write!(
+                    f,
+                    "the referenced transaction is a withdrawal and can not be disputed"
+                )
+            }
+            Error::AccountLocked => write!(f, "the account of the client is locked"),
+            Error::DuplicateTransaction => {
+                write!(
+                    f,
+                    "a transaction with the same ID has already been processed"
+                )
+            }
+            Error::Overflow => write!(f, "a balance of the client would overflow"),
+            Error::DbLayer(e) => write!(f, "database error: {}", e),
+            Error::Writer(e) => write!(f, "writer error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
@@ -43,18 +136,29 @@ pub struct Transaction {
     pub client: u16,
 
     /// A unique transaction ID given to deposits or withdrawals. Disputes, resolutions, and
-    /// chargebacks reference transaction IDs of deposits
+    /// chargebacks reference transaction IDs of deposits or withdrawals
     pub tx: u32,
 
     /// The amount of the deposit or withdrawal. This field will be None for any other TransactionType
     #[serde(default)]
     pub amount: Option<i64>,
 
-    pub disputed: bool,
+    /// The stage of the dispute process the deposit or withdrawal is in
+    pub state: DisputeState,
+}
+
+/// The stages of the dispute process a deposit or withdrawal moves through. A transaction starts as
+/// `Normal`, may be `Disputed`, and a dispute ends either `Resolved` or `ChargedBack`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DisputeState {
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
 }
 
 /// A single transaction meant to be readable by a human
-#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
+#[derive(Serialize, Debug, PartialEq, Copy, Clone)]
 pub struct HumanReadableTransaction {
     /// Types including deposits, withdrawals, disputes, resolutions of disputes, and chargebacks
     #[serde(rename = "type")]
@@ -64,11 +168,10 @@ pub struct HumanReadableTransaction {
     pub client: u16,
 
     /// A unique transaction ID given to deposits or withdrawals. Disputes, resolutions, and
-    /// chargebacks reference transaction IDs of deposits
+    /// chargebacks reference transaction IDs of deposits or withdrawals
     pub tx: u32,
 
     /// The amount of the deposit or withdrawal. This field will be None for any other TransactionType
-    #[serde(default, deserialize_with = "fixed_point_util::deserialize")]
     pub amount: Option<i64>,
 }
 
@@ -79,7 +182,7 @@ impl From<HumanReadableTransaction> for Transaction {
             client: transaction.client,
             tx: transaction.tx,
             amount: transaction.amount,
-            disputed: false,
+            state: DisputeState::Normal,
         }
     }
 }
@@ -136,3 +239,207 @@ impl From<Client> for HumanReadableClient {
         }
     }
 }
+
+/// Where an ingested transaction was read from, stored by the [`crate::db_layer::DbLayer`] along
+/// with the last transaction of each client read from a source such that reading the source again
+/// neither misses nor repeats any transaction
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Cursor {
+    /// Assigned to every record read, increasing by one with each record
+    pub sequence: u64,
+
+    /// The identity of the source the transaction was read from, such as the canonical path of a
+    /// file
+    pub source: String,
+
+    /// The byte offset in the source right after the transaction's record, or None if the source
+    /// can't be read from an offset
+    pub offset: Option<u64>,
+}
+
+/// Where a record ends in a source which can be read again from an offset
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Position {
+    /// The byte offset right after the record
+    pub offset: u64,
+
+    /// A hash of every record up to the offset, such that a source whose records changed since it
+    /// was read is told apart from one which was only appended to
+    pub fingerprint: u64,
+}
+
+impl Position {
+    /// The start of a source, before any record
+    pub const START: Position = Position {
+        offset: 0,
+        fingerprint: 0xcbf2_9ce4_8422_2325,
+    };
+}
+
+/// Where reading a source which can be read from an offset carries on from, stored by the
+/// [`crate::db_layer::DbLayer`] for every such source. Every record before `position` has been
+/// processed, however many workers there are, and the record at it is given `sequence`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ResumePoint {
+    pub sequence: u64,
+    pub position: Position,
+}
+
+/// A single event in the audit log of a client: a transaction which was either applied or refused,
+/// along with the client's balances before and after it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AuditEntry {
+    /// Assigned by the [`crate::db_layer::DbLayer`] when the entry is written, increasing in the
+    /// order entries are written
+    pub id: u64,
+
+    /// When the transaction was processed, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// The transaction as it was submitted
+    pub transaction: Transaction,
+
+    /// The name of the [`Error`] variant which caused the transaction to be refused, or None if it
+    /// was applied
+    pub error: Option<String>,
+
+    /// The client before the transaction. A client without any applied transaction has no funds
+    pub before: Client,
+
+    /// The client after the transaction, which is the same as `before` if it was refused
+    pub after: Client,
+}
+
+/// An [`AuditEntry`] to be output by the application in a human readable format
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct HumanReadableAuditEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(serialize_with = "fixed_point_util::serialize_option")]
+    pub amount: Option<i64>,
+    pub error: Option<String>,
+    pub before: HumanReadableClient,
+    pub after: HumanReadableClient,
+}
+
+impl From<AuditEntry> for HumanReadableAuditEntry {
+    fn from(entry: AuditEntry) -> HumanReadableAuditEntry {
+        HumanReadableAuditEntry {
+            id: entry.id,
+            timestamp: entry.timestamp,
+            ty: entry.transaction.ty,
+            client: entry.transaction.client,
+            tx: entry.transaction.tx,
+            amount: entry.transaction.amount,
+            error: entry.error,
+            before: entry.before.into(),
+            after: entry.after.into(),
+        }
+    }
+}
+
+/// A transaction that was refused by the transaction processor along with why it was refused
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Rejection {
+    /// The type of the refused transaction
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+
+    /// The client ID given in the refused transaction
+    pub client: u16,
+
+    /// The transaction ID given in the refused transaction
+    pub tx: u32,
+
+    /// The amount given in the refused transaction if any
+    #[serde(serialize_with = "fixed_point_util::serialize_option")]
+    pub amount: Option<i64>,
+
+    /// The name of the [`Error`] variant which caused the refusal
+    pub error: &'static str,
+
+    /// A description of the [`Error`] meant to be read by a human
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(transaction: Transaction, error: &Error) -> Rejection {
+        Rejection {
+            ty: transaction.ty,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            error: error.variant(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+/// Whether a submitted transaction was processed, as reported back to its source
+#[derive(Serialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The transaction was applied
+    Accepted,
+    /// The transaction was refused by the transaction processor
+    Rejected,
+    /// The record could not be read as a transaction
+    Malformed,
+}
+
+/// The outcome of processing a single submitted transaction which is sent back to the source the
+/// transaction was read from
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Acknowledgement {
+    /// Whether the transaction was applied
+    pub status: Status,
+
+    /// The line of the source on which the record begins, which tells acknowledgements apart even
+    /// when the record could not be read
+    pub line: u64,
+
+    /// The transaction ID given in the transaction, if the record could be read at all
+    pub tx: Option<u32>,
+
+    /// The name of the [`Error`] variant which caused the refusal if any
+    pub error: Option<&'static str>,
+
+    /// A description of why the transaction was not applied meant to be read by a human
+    pub reason: Option<String>,
+}
+
+impl Acknowledgement {
+    pub fn accepted(line: u64, tx: u32) -> Acknowledgement {
+        Acknowledgement {
+            status: Status::Accepted,
+            line,
+            tx: Some(tx),
+            error: None,
+            reason: None,
+        }
+    }
+
+    pub fn rejected(line: u64, tx: u32, error: &Error) -> Acknowledgement {
+        Acknowledgement {
+            status: Status::Rejected,
+            line,
+            tx: Some(tx),
+            error: Some(error.variant()),
+            reason: Some(error.to_string()),
+        }
+    }
+
+    pub fn malformed(line: u64, reason: String) -> Acknowledgement {
+        Acknowledgement {
+            status: Status::Malformed,
+            line,
+            tx: None,
+            error: None,
+            reason: Some(reason),
+        }
+    }
+}
@@ -1,78 +1,371 @@
-use std::path::Path;
-use tokio::{fs::File, sync::mpsc};
-use tokio_stream::StreamExt;
+use csv_async::StringRecord;
+use serde::Deserialize;
+use std::{io::SeekFrom, path::Path};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeekExt},
+    sync::mpsc,
+};
 
 use super::*;
-use crate::{HumanReadableTransaction, Transaction};
+use crate::{
+    fixed_point_util::{self, Rounding},
+    Acknowledgement, HumanReadableTransaction, Position, Transaction, TransactionType,
+};
 
-/// An Implementor of the TransactionReader trait which reads CSV values from a given file
+/// An Implementor of the TransactionReader trait which reads CSV values from a given file or any
+/// other [`AsyncRead`] implementor such as stdin
 pub struct CsvReader {
-    /// The file to read from
-    file: File,
+    /// The source to read from
+    source: Box<dyn AsyncRead + Unpin + Send + Sync>,
 
     /// The [`mpsc::Sender`] through which read transactions will be sent
-    sender: mpsc::Sender<Transaction>,
+    sender: mpsc::Sender<Submission>,
 
     /// The [`mpsc::Receiver`] from which [`Transaction`]s will be received. Will be None after the
     /// [`TransactionReader::start`] method is called
-    receiver: Option<mpsc::Receiver<Transaction>>,
+    receiver: Option<mpsc::Receiver<Submission>>,
+
+    /// The [`mpsc::Sender`] through which the source is sent an [`Acknowledgement`] for each of its
+    /// transactions, if it expects any
+    reply: Option<mpsc::Sender<Acknowledgement>>,
+
+    /// Where in the file the source starts, or None if the source isn't a file
+    start: Option<Position>,
+
+    /// The header line of the file when resuming it past its header
+    headers: Option<StringRecord>,
+
+    /// How amounts with more than four digits behind the decimal point are handled
+    rounding: Rounding,
+}
+
+/// A [`HumanReadableTransaction`] as written in CSV, where the amount is kept as text such that it
+/// can be parsed with the [`Rounding`] of the reader
+#[derive(Deserialize)]
+struct CsvTransaction {
+    #[serde(rename = "type")]
+    ty: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
 }
 
 impl CsvReader {
-    pub async fn new(file: impl AsRef<Path>, buffer_size: usize) -> std::io::Result<Self> {
-        let file = File::open(file).await?;
+    pub async fn new(
+        file: impl AsRef<Path>,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> std::io::Result<Self> {
+        Self::resume(file, Position::START, buffer_size, rounding).await
+    }
+
+    /// Read a file from the given [`Position`] on, as given by [`Submission::position`]. Any header
+    /// line at the start of the file still applies. Whether the file still holds the records it was
+    /// read with is not checked, see [`verify`]
+    pub async fn resume(
+        file: impl AsRef<Path>,
+        position: Position,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> std::io::Result<Self> {
+        let headers = if position.offset > 0 {
+            read_headers(File::open(&file).await?).await?
+        } else {
+            None
+        };
+
+        let mut source = File::open(file).await?;
+        source.seek(SeekFrom::Start(position.offset)).await?;
+
+        let mut reader = Self::from_reader(source, buffer_size, rounding);
+        reader.start = Some(position);
+        reader.headers = headers;
+        Ok(reader)
+    }
+
+    pub fn from_reader(
+        source: impl AsyncRead + Unpin + Send + Sync + 'static,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(buffer_size);
         let receiver = Some(receiver);
 
-        Ok(Self {
-            file,
+        Self {
+            source: Box::new(source),
             sender,
             receiver,
-        })
+            reply: None,
+            start: None,
+            headers: None,
+            rounding,
+        }
+    }
+
+    /// Create a `CsvReader` which sends to an existing channel rather than creating its own, such
+    /// that several sources may be merged into a single [`mpsc::Receiver`], and which gives each
+    /// [`Submission`] a handle through which to send its [`Acknowledgement`] to `reply`. The
+    /// returned reader must be driven with [`CsvReader::read`] rather than
+    /// [`TransactionReader::start`]
+    pub(super) fn with_sender(
+        source: impl AsyncRead + Unpin + Send + Sync + 'static,
+        sender: mpsc::Sender<Submission>,
+        reply: mpsc::Sender<Acknowledgement>,
+        rounding: Rounding,
+    ) -> Self {
+        Self {
+            source: Box::new(source),
+            sender,
+            receiver: None,
+            reply: Some(reply),
+            start: None,
+            headers: None,
+            rounding,
+        }
     }
 
-    async fn read(self) {
-        let mut reader = csv_async::AsyncReaderBuilder::new()
-            .trim(csv_async::Trim::All)
-            .flexible(true)
-            .create_deserializer(self.file);
-        let reader = reader.deserialize();
-
-        let mut reader = reader.filter_map(
-            |result: Result<HumanReadableTransaction, _>| -> Option<Transaction> {
-                // TODO: Return an error instaed of skipping over lines that don't deserialize properly
-                if let Ok(result) = result {
-                    let result = result.into();
-                    Some(result)
-                } else {
-                    None
+    /// Read until the end of the source, the receiver being closed, or the reader being shut down
+    pub(super) async fn read(self, mut shutdown: ShutdownSignal) {
+        let Self {
+            source,
+            sender,
+            reply,
+            start,
+            mut headers,
+            rounding,
+            ..
+        } = self;
+        let mut reader = create_reader(source);
+        let mut fingerprint = start.map_or(Position::START.fingerprint, |start| start.fingerprint);
+
+        // Each record is read as a `StringRecord` before being deserialized such that the record
+        // can be included in any `ReadError`
+        let mut record = StringRecord::new();
+        loop {
+            let result = tokio::select! {
+                result = reader.read_record(&mut record) => result,
+                _ = shutdown.recv() => break,
+            };
+            if matches!(result, Ok(true)) && !is_blank(&record) {
+                fingerprint = fold(fingerprint, &record);
+            }
+
+            let transaction = match result {
+                Ok(false) => break,
+
+                // Blank lines are not considered records at all
+                Ok(true) if is_blank(&record) => continue,
+
+                // The header line is optional such that line-delimited records without one, as may
+                // be sent over a network stream, can be read as well as complete CSV files
+                Ok(true) if headers.is_none() && is_header(&record) => {
+                    headers = Some(record.clone());
+                    continue;
                 }
-            },
-        );
-
-        // The method then populates the buffer of the channel until it is full, waiting for a spot
-        // to become available before continuing ensuring that there are never more than the
-        // configured amount of transactions in the queue
-        while let Some(transaction) = reader.next().await {
-            // Send the transaction and break the loop if the send is an Err as that means the
-            // receiver has been closed
-            if self.sender.send(transaction).await.is_err() {
+
+                Ok(true) => {
+                    let headers = headers.get_or_insert_with(default_headers);
+                    parse(&record, headers, rounding)
+                }
+
+                // Nothing more can be read once the underlying source fails
+                Err(e) if matches!(e.kind(), csv_async::ErrorKind::Io(_)) => {
+                    let error = read_error(e, None);
+                    submit(&sender, reply.as_ref(), error.line, Err(error), None).await;
+                    break;
+                }
+
+                Err(e) => Err(read_error(e, None)),
+            };
+
+            // The method then populates the buffer of the channel until it is full, waiting for a
+            // spot to become available before continuing ensuring that there are never more than
+            // the configured amount of transactions in the queue. Break the loop if the send is an
+            // Err as that means the receiver has been closed
+            let line = match &transaction {
+                Ok(_) => record.position().map_or(0, |position| position.line()),
+                Err(e) => e.line,
+            };
+            let position = start.map(|start| Position {
+                offset: start.offset + reader.position().byte(),
+                fingerprint,
+            });
+            if !submit(&sender, reply.as_ref(), line, transaction, position).await {
                 break;
             }
         }
     }
 }
 
+/// Parse a single record as a [`Transaction`], the columns being named by `headers`
+fn parse(
+    record: &StringRecord,
+    headers: &StringRecord,
+    rounding: Rounding,
+) -> Result<Transaction, ReadError> {
+    let transaction = record
+        .deserialize::<CsvTransaction>(Some(headers))
+        .map_err(|e| read_error(e, Some(record)))?;
+    let amount = match transaction.amount {
+        Some(amount) => Some(fixed_point_util::parse(&amount, rounding).map_err(|e| {
+            ReadError {
+                line: record
+                    .position()
+                    .map(|position| position.line())
+                    .unwrap_or_default(),
+                record: record.iter().collect::<Vec<_>>().join(","),
+                error: Box::new(e),
+            }
+        })?),
+        None => None,
+    };
+
+    Ok(HumanReadableTransaction {
+        ty: transaction.ty,
+        client: transaction.client,
+        tx: transaction.tx,
+        amount,
+    }
+    .into())
+}
+
+/// Create the CSV reader used for every source
+fn create_reader(
+    source: impl AsyncRead + Unpin + Send + Sync + 'static,
+) -> csv_async::AsyncReader<impl AsyncRead + Unpin + Send + Sync> {
+    csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .flexible(true)
+        .has_headers(false)
+        .create_reader(source)
+}
+
+/// Whether a file still holds the records it held when it was read up to the given [`Position`],
+/// such that it can be resumed from there. A file which was only appended to since still does
+pub async fn verify(file: impl AsRef<Path>, position: Position) -> std::io::Result<bool> {
+    let mut reader = create_reader(File::open(file).await?);
+    let mut record = StringRecord::new();
+    let mut fingerprint = Position::START.fingerprint;
+
+    // Records are folded into the fingerprint exactly as they are by `CsvReader::read`
+    while reader.position().byte() < position.offset {
+        match reader.read_record(&mut record).await {
+            Ok(false) => return Ok(false),
+            Ok(true) if !is_blank(&record) => fingerprint = fold(fingerprint, &record),
+            Ok(true) => {}
+            Err(e) if matches!(e.kind(), csv_async::ErrorKind::Io(_)) => return Err(e.into()),
+            Err(_) => {}
+        }
+    }
+
+    Ok(reader.position().byte() == position.offset && fingerprint == position.fingerprint)
+}
+
+/// Fold a record into the fingerprint of the records before it with 64-bit FNV-1a, which unlike
+/// the hasher of the standard library stays the same between Rust versions. Every field and the
+/// record itself are terminated by bytes which never occur in UTF-8, such that text moved between
+/// fields or records still changes the fingerprint
+fn fold(fingerprint: u64, record: &StringRecord) -> u64 {
+    record
+        .iter()
+        .flat_map(|field| field.bytes().chain(std::iter::once(0xff)))
+        .chain(std::iter::once(0xfe))
+        .fold(fingerprint, |fingerprint, byte| {
+            (fingerprint ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Whether a record is a blank line, which isn't considered a record at all
+fn is_blank(record: &StringRecord) -> bool {
+    record.iter().all(|field| field.is_empty())
+}
+
+/// Read the header line of a file if it has one
+async fn read_headers(file: File) -> std::io::Result<Option<StringRecord>> {
+    let mut reader = create_reader(file);
+    let mut record = StringRecord::new();
+    while reader.read_record(&mut record).await? {
+        if !is_blank(&record) {
+            return Ok(Some(record).filter(is_header));
+        }
+    }
+    Ok(None)
+}
+
+/// Send a transaction to the processor along with a [`Reply`] for its acknowledgement if `reply` is
+/// given, returning false if the receiver has been closed
+async fn submit(
+    sender: &mpsc::Sender<Submission>,
+    reply: Option<&mpsc::Sender<Acknowledgement>>,
+    line: u64,
+    transaction: Result<Transaction, ReadError>,
+    position: Option<Position>,
+) -> bool {
+    // Room for the acknowledgement is reserved before the transaction is sent such that a source
+    // which doesn't read its acknowledgements only ever holds up its own transactions
+    let reply = match reply {
+        Some(reply) => reply
+            .clone()
+            .reserve_owned()
+            .await
+            .ok()
+            .map(|permit| Reply { permit, line }),
+        None => None,
+    };
+
+    sender
+        .send(Submission {
+            transaction,
+            reply,
+            position,
+        })
+        .await
+        .is_ok()
+}
+
+/// The headers assumed for records which aren't preceded by a header line
+fn default_headers() -> StringRecord {
+    StringRecord::from(vec!["type", "client", "tx", "amount"])
+}
+
+/// Whether a record is a header line rather than a transaction
+fn is_header(record: &StringRecord) -> bool {
+    record
+        .get(0)
+        .is_some_and(|field| field.eq_ignore_ascii_case("type"))
+}
+
+/// Create a [`ReadError`] from a [`csv_async::Error`] and the record which caused it if the record
+/// could be read at all
+fn read_error(error: csv_async::Error, record: Option<&StringRecord>) -> ReadError {
+    let line = record
+        .and_then(|record| record.position())
+        .or_else(|| error.position())
+        .map(|position| position.line())
+        .unwrap_or_default();
+    let record = record
+        .map(|record| record.iter().collect::<Vec<_>>().join(","))
+        .unwrap_or_default();
+
+    ReadError {
+        line,
+        record,
+        error: Box::new(error),
+    }
+}
+
 impl TransactionReader for CsvReader {
-    fn start(mut self) -> mpsc::Receiver<Transaction> {
+    fn start(mut self) -> (mpsc::Receiver<Submission>, Shutdown) {
         let receiver = self.receiver.take();
+        let (shutdown, signal) = Shutdown::new();
 
-        tokio::spawn(self.read());
+        tokio::spawn(self.read(signal));
 
         // The `unwrap` is definitely code smell, but the receiver will always be Some upon creation
         // and the `new` and `start` methods are the only public methods, so if receiver is None,
         // something has gone seriously wrong
-        receiver.unwrap()
+        (receiver.unwrap(), shutdown)
     }
 }
 
@@ -80,10 +373,12 @@ impl TransactionReader for CsvReader {
 mod tests {
     use super::*;
 
-    use std::{convert::TryInto, path::PathBuf};
+    use std::path::PathBuf;
     use tempfile::TempDir;
     use tokio::io::AsyncWriteExt;
 
+    use crate::{DisputeState, TransactionType};
+
     #[tokio::test]
     async fn basic() {
         let dir = TempDir::new_in("./").unwrap();
@@ -104,35 +399,35 @@ mod tests {
                 client: 1,
                 tx: 1,
                 amount: Some(10000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 2,
                 tx: 2,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 1,
                 tx: 3,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 1,
                 tx: 4,
                 amount: Some(15000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 2,
                 tx: 5,
                 amount: Some(30000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
         ];
 
@@ -142,19 +437,19 @@ mod tests {
             file.write_all(file_contents.as_bytes()).await.unwrap();
         }
 
-        let reader = CsvReader::new(&path, 2).await.unwrap();
-        let mut receiver = reader.start();
+        let reader = CsvReader::new(&path, 2, Rounding::Reject).await.unwrap();
+        let (mut receiver, _) = reader.start();
 
         let mut actual = Vec::new();
         while let Some(transaction) = receiver.recv().await {
-            actual.push(transaction);
+            actual.push(transaction.transaction.unwrap());
         }
 
         assert_eq!(expected, actual);
     }
 
     #[tokio::test]
-    async fn skip_malformed_data() {
+    async fn report_malformed_data() {
         let dir = TempDir::new_in("./").unwrap();
         let mut path: PathBuf = dir.path().into();
         path.push("test.csv");
@@ -174,35 +469,35 @@ mod tests {
                 client: 1,
                 tx: 1,
                 amount: Some(10000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 2,
                 tx: 2,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 1,
                 tx: 3,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 1,
                 tx: 4,
                 amount: Some(15000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 2,
                 tx: 5,
                 amount: Some(30000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
         ];
 
@@ -211,15 +506,22 @@ mod tests {
             file.write_all(file_contents.as_bytes()).await.unwrap();
         }
 
-        let reader = CsvReader::new(&path, 2).await.unwrap();
-        let mut receiver = reader.start();
+        let reader = CsvReader::new(&path, 2, Rounding::Reject).await.unwrap();
+        let (mut receiver, _) = reader.start();
 
         let mut actual = Vec::new();
+        let mut errors = Vec::new();
         while let Some(transaction) = receiver.recv().await {
-            actual.push(transaction);
+            match transaction.transaction {
+                Ok(transaction) => actual.push(transaction),
+                Err(e) => errors.push(e),
+            }
         }
 
         assert_eq!(expected, actual);
+        assert_eq!(1, errors.len());
+        assert_eq!(5, errors[0].line);
+        assert_eq!("deposit,invalid,2,2.0", errors[0].record);
     }
 
     #[tokio::test]
@@ -241,28 +543,28 @@ mod tests {
                 client: 1,
                 tx: 1,
                 amount: Some(10000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Dispute,
                 client: 1,
                 tx: 1,
                 amount: None,
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Resolve,
                 client: 1,
                 tx: 1,
                 amount: None,
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Chargeback,
                 client: 1,
                 tx: 1,
                 amount: None,
-                disputed: false,
+                state: DisputeState::Normal,
             },
         ];
 
@@ -271,14 +573,109 @@ mod tests {
             file.write_all(file_contents.as_bytes()).await.unwrap();
         }
 
-        let reader = CsvReader::new(&path, 2).await.unwrap();
-        let mut receiver = reader.start();
+        let reader = CsvReader::new(&path, 2, Rounding::Reject).await.unwrap();
+        let (mut receiver, _) = reader.start();
 
         let mut actual = Vec::new();
         while let Some(transaction) = receiver.recv().await {
-            actual.push(transaction);
+            actual.push(transaction.transaction.unwrap());
         }
 
         assert_eq!(expected, actual);
     }
+
+    #[tokio::test]
+    async fn shutdown() {
+        // The writing end is kept open such that the reader never reaches the end of its source
+        let (mut source, stream) = tokio::io::duplex(64);
+        source
+            .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n")
+            .await
+            .unwrap();
+
+        let reader = CsvReader::from_reader(stream, 2, Rounding::Reject);
+        let (mut receiver, shutdown) = reader.start();
+
+        let transaction = receiver.recv().await.unwrap().transaction.unwrap();
+        assert_eq!(1, transaction.tx);
+
+        shutdown.shutdown();
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut path: PathBuf = dir.path().into();
+        path.push("test.csv");
+
+        // The columns are out of their usual order such that the header has to apply when resuming
+        let file_contents =
+            "type,tx,client,amount\ndeposit,1,1,1.0\n\ndeposit,2,2,2.0\r\nwithdrawal,3,1,0.5\n";
+        {
+            let mut file = File::create(&path).await.unwrap();
+            file.write_all(file_contents.as_bytes()).await.unwrap();
+        }
+
+        let read = |reader: CsvReader| async move {
+            let (mut receiver, _) = reader.start();
+            let mut submissions = Vec::new();
+            while let Some(submission) = receiver.recv().await {
+                let transaction = submission.transaction.unwrap();
+                submissions.push((transaction.tx, submission.position.unwrap()));
+            }
+            submissions
+        };
+        let offsets = |submissions: &[(u32, Position)]| {
+            submissions
+                .iter()
+                .map(|(tx, position)| (*tx, position.offset))
+                .collect::<Vec<_>>()
+        };
+
+        let submissions = read(CsvReader::new(&path, 2, Rounding::Reject).await.unwrap()).await;
+        assert_eq!(vec![(1, 38), (2, 55), (3, 75)], offsets(&submissions));
+        assert_eq!(file_contents.len() as u64, submissions[2].1.offset);
+
+        // Resuming after a record reads every record after it with the same positions
+        let resumed = read(
+            CsvReader::resume(&path, submissions[0].1, 2, Rounding::Reject)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(submissions[1..], resumed[..]);
+
+        let resumed = read(
+            CsvReader::resume(&path, submissions[2].1, 2, Rounding::Reject)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(resumed.is_empty());
+
+        // A file which was appended to can still be resumed, but not one whose records changed
+        assert!(verify(&path, Position::START).await.unwrap());
+        {
+            let mut file = tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .await
+                .unwrap();
+            file.write_all(b"deposit,4,1,1.0\n").await.unwrap();
+        }
+        for (_, position) in submissions.iter() {
+            assert!(verify(&path, *position).await.unwrap());
+        }
+        let changed = file_contents.replace("2.0", "3.0");
+        tokio::fs::write(&path, changed).await.unwrap();
+        assert!(verify(&path, submissions[0].1).await.unwrap());
+        assert!(!verify(&path, submissions[1].1).await.unwrap());
+        assert!(!verify(&path, submissions[2].1).await.unwrap());
+
+        // Stdin and other streams can't be resumed
+        let stream = std::io::Cursor::new(file_contents.as_bytes().to_vec());
+        let (mut receiver, _) = CsvReader::from_reader(stream, 2, Rounding::Reject).start();
+        assert_eq!(None, receiver.recv().await.unwrap().position);
+    }
 }
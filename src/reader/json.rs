@@ -0,0 +1,231 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::mpsc,
+};
+
+use super::*;
+use crate::{
+    fixed_point_util::{self, Rounding},
+    HumanReadableTransaction, TransactionType,
+};
+
+/// An implementor of the TransactionReader trait which reads JSON-lines, one
+/// [`HumanReadableTransaction`] object per line, from a given file or any other [`AsyncRead`]
+/// implementor such as stdin. Amounts may be given as strings or as numbers and are parsed exactly
+/// from their decimal representation either way
+pub struct JsonReader {
+    /// The source to read from
+    source: Box<dyn AsyncRead + Unpin + Send + Sync>,
+
+    /// The [`mpsc::Sender`] through which read transactions will be sent
+    sender: mpsc::Sender<Submission>,
+
+    /// The [`mpsc::Receiver`] from which [`Transaction`]s will be received. Will be None after the
+    /// [`TransactionReader::start`] method is called
+    receiver: Option<mpsc::Receiver<Submission>>,
+
+    /// How amounts with more than four digits behind the decimal point are handled
+    rounding: Rounding,
+}
+
+/// A [`HumanReadableTransaction`] as written in JSON, where the amount is kept as a [`Value`] such
+/// that a number can be parsed from its exact text rather than through a float
+#[derive(Deserialize)]
+struct JsonTransaction {
+    #[serde(rename = "type")]
+    ty: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(default)]
+    amount: Option<Value>,
+}
+
+impl JsonTransaction {
+    /// Parse the amount with the given [`Rounding`]
+    fn parse(
+        self,
+        rounding: Rounding,
+    ) -> Result<HumanReadableTransaction, Box<dyn std::error::Error + Send + Sync>> {
+        let amount = match self.amount {
+            None | Some(Value::Null) => None,
+            Some(Value::String(amount)) => Some(fixed_point_util::parse(&amount, rounding)?),
+            // Numbers keep their exact text as serde_json's `arbitrary_precision` is enabled
+            Some(Value::Number(amount)) => {
+                Some(fixed_point_util::parse(&amount.to_string(), rounding)?)
+            }
+            Some(other) => return Err(format!("invalid amount `{}`", other).into()),
+        };
+
+        Ok(HumanReadableTransaction {
+            ty: self.ty,
+            client: self.client,
+            tx: self.tx,
+            amount,
+        })
+    }
+}
+
+impl JsonReader {
+    pub async fn new(
+        file: impl AsRef<Path>,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> std::io::Result<Self> {
+        let file = File::open(file).await?;
+        Ok(Self::from_reader(file, buffer_size, rounding))
+    }
+
+    pub fn from_reader(
+        source: impl AsyncRead + Unpin + Send + Sync + 'static,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(buffer_size);
+        let receiver = Some(receiver);
+
+        Self {
+            source: Box::new(source),
+            sender,
+            receiver,
+            rounding,
+        }
+    }
+
+    /// Read line by line until the end of the source, the receiver being closed, or the reader
+    /// being shut down
+    async fn read(self, mut shutdown: ShutdownSignal) {
+        let mut lines = BufReader::new(self.source).lines();
+        let mut line = 0;
+
+        loop {
+            let result = tokio::select! {
+                result = lines.next_line() => result,
+                _ = shutdown.recv() => break,
+            };
+            line += 1;
+
+            let transaction = match result {
+                Ok(None) => break,
+
+                // Blank lines are not considered records at all
+                Ok(Some(record)) if record.trim().is_empty() => continue,
+
+                Ok(Some(record)) => parse(&record, self.rounding).map_err(|error| ReadError {
+                    line,
+                    record,
+                    error,
+                }),
+
+                // Nothing more can be read once the underlying source fails
+                Err(e) => {
+                    let error = ReadError {
+                        line,
+                        record: String::new(),
+                        error: Box::new(e),
+                    };
+                    let _ = self
+                        .sender
+                        .send(Submission {
+                            transaction: Err(error),
+                            reply: None,
+                            position: None,
+                        })
+                        .await;
+                    break;
+                }
+            };
+
+            // Break the loop if the send is an Err as that means the receiver has been closed
+            let submission = Submission {
+                transaction,
+                reply: None,
+                position: None,
+            };
+            if self.sender.send(submission).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Parse a single line as a [`Transaction`]
+fn parse(
+    record: &str,
+    rounding: Rounding,
+) -> Result<Transaction, Box<dyn std::error::Error + Send + Sync>> {
+    let transaction: JsonTransaction = serde_json::from_str(record)?;
+    Ok(transaction.parse(rounding)?.into())
+}
+
+impl TransactionReader for JsonReader {
+    fn start(mut self) -> (mpsc::Receiver<Submission>, Shutdown) {
+        let receiver = self.receiver.take();
+        let (shutdown, signal) = Shutdown::new();
+
+        tokio::spawn(self.read(signal));
+
+        // As with the `CsvReader`, the receiver is always Some until `start` consumes the reader
+        (receiver.unwrap(), shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::DisputeState;
+
+    #[tokio::test]
+    async fn basic() {
+        let source = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "deposit", "client": 2, "tx": 2, "amount": 2.0001}
+
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.5}
+{"type": "deposit", "client": 1, "tx": 4, "amount": 0.00001}
+{"type": "deposit", "client": "x", "tx": 5, "amount": 1}
+{"type": "dispute", "client": 2, "tx": 2}
+{"type": "resolve", "client": 2, "tx": 2, "amount": null}
+"#;
+
+        let transaction = |ty, client, tx, amount| Transaction {
+            ty,
+            client,
+            tx,
+            amount,
+            state: DisputeState::Normal,
+        };
+        let expected = vec![
+            transaction(TransactionType::Deposit, 1, 1, Some(10000)),
+            transaction(TransactionType::Deposit, 2, 2, Some(20001)),
+            transaction(TransactionType::Withdrawal, 1, 3, Some(5000)),
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::Resolve, 2, 2, None),
+        ];
+
+        let reader = JsonReader::from_reader(source.as_bytes(), 2, Rounding::Reject);
+        let (mut receiver, _) = reader.start();
+
+        let mut actual = Vec::new();
+        let mut errors = Vec::new();
+        while let Some(submission) = receiver.recv().await {
+            match submission.transaction {
+                Ok(transaction) => actual.push(transaction),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        assert_eq!(expected, actual);
+        assert_eq!(
+            vec![5, 6],
+            errors.iter().map(|e| e.line).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            r#"{"type": "deposit", "client": 1, "tx": 4, "amount": 0.00001}"#,
+            errors[0].record
+        );
+    }
+}
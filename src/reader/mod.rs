@@ -1,16 +1,103 @@
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
-use super::Transaction;
+use super::{Acknowledgement, Position, Transaction};
 
 pub mod csv;
+pub mod json;
+pub mod tcp;
+
+/// An error encountered while reading a single [`Transaction`] from its source. Any record which
+/// fails to be read is sent to the [`mpsc::Receiver`] as a `ReadError` such that the caller may
+/// decide whether to skip the record, count it, or abort entirely
+#[derive(Debug)]
+pub struct ReadError {
+    /// The line of the source on which the record begins
+    pub line: u64,
+
+    /// The record exactly as it was read from the source
+    pub record: String,
+
+    /// The error returned when attempting to read the record as a [`Transaction`]
+    pub error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "malformed record on line {} ({}): {}",
+            self.line, self.record, self.error
+        )
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// A [`Transaction`], or the error encountered reading it, along with the [`Reply`] through which
+/// its [`Acknowledgement`] is sent back to the source it was read from. Sources which don't expect
+/// to hear back, such as files, don't provide one
+#[derive(Debug)]
+pub struct Submission {
+    pub transaction: Result<Transaction, ReadError>,
+    pub reply: Option<Reply>,
+
+    /// Where the record ends in the source, for sources which can be read again from an offset
+    pub position: Option<Position>,
+}
+
+/// The handle through which the [`Acknowledgement`] of a single record is sent back to its source
+#[derive(Debug)]
+pub struct Reply {
+    /// Room for the acknowledgement reserved in the channel of the source
+    pub permit: mpsc::OwnedPermit<Acknowledgement>,
+
+    /// The line of the source on which the record begins
+    pub line: u64,
+}
+
+impl Reply {
+    pub fn send(self, acknowledgement: Acknowledgement) {
+        self.permit.send(acknowledgement);
+    }
+}
+
+/// A handle through which a started [`TransactionReader`] is told to stop reading. Transactions
+/// which have already been read are still received, after which the [`mpsc::Receiver`] is closed.
+/// Dropping the handle without calling [`Shutdown::shutdown`] lets the reader run to completion
+#[derive(Debug)]
+pub struct Shutdown(watch::Sender<bool>);
+
+impl Shutdown {
+    fn new() -> (Shutdown, ShutdownSignal) {
+        let (sender, receiver) = watch::channel(false);
+        (Shutdown(sender), ShutdownSignal(receiver))
+    }
+
+    /// Stop the reader from reading any more transactions
+    pub fn shutdown(&self) {
+        // An error only means every task of the reader has already finished
+        let _ = self.0.send(true);
+    }
+}
+
+/// The end of a [`Shutdown`] held by each task of a reader
+#[derive(Debug, Clone)]
+struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// Wait until [`Shutdown::shutdown`] is called, or forever if the handle has been dropped
+    async fn recv(&mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
 
 /// Implementors of this trait provide a method which begin the reading of transactions from an
 /// arbitrary source and send it to a returned [`mpsc::Receiver`] which may be read from to begin
-/// transaction processing
-
-// TODO: A way of cancelling a Reader for something like a TCP stream
-// TODO: A way of sending back errors to something like a TCP stream eg. if there is an attemped
-// withdrawal above the available funds
+/// transaction processing, along with a [`Shutdown`] handle to stop reading early
 pub trait TransactionReader {
-    fn start(self) -> mpsc::Receiver<Transaction>;
+    fn start(self) -> (mpsc::Receiver<Submission>, Shutdown);
 }
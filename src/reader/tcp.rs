@@ -0,0 +1,275 @@
+use std::net::SocketAddr;
+use tokio::{
+    net::{tcp::OwnedWriteHalf, TcpListener, ToSocketAddrs},
+    sync::mpsc,
+};
+
+use super::{csv::CsvReader, *};
+use crate::fixed_point_util::Rounding;
+
+/// An implementor of the TransactionReader trait which accepts any number of concurrent TCP
+/// connections, each sending CSV records with or without a header line, and merges the
+/// transactions of every connection into a single [`mpsc::Receiver`]. Transactions from one
+/// connection are received in the order they were sent, but there is no ordering between
+/// connections. Every transaction is acknowledged back to its connection as a CSV record of its
+/// [`Acknowledgement`] once processed, which carries the line of the connection the record began on
+pub struct TcpReader {
+    /// The socket on which connections are accepted
+    listener: TcpListener,
+
+    /// The [`mpsc::Sender`] shared by the reader of every connection
+    sender: mpsc::Sender<Submission>,
+
+    /// The [`mpsc::Receiver`] from which [`Transaction`]s will be received
+    receiver: mpsc::Receiver<Submission>,
+
+    /// The number of acknowledgements each connection may have waiting to be written
+    buffer_size: usize,
+
+    /// How amounts with more than four digits behind the decimal point are handled
+    rounding: Rounding,
+}
+
+impl TcpReader {
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        buffer_size: usize,
+        rounding: Rounding,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (sender, receiver) = mpsc::channel(buffer_size);
+
+        Ok(Self {
+            listener,
+            sender,
+            receiver,
+            buffer_size,
+            rounding,
+        })
+    }
+
+    /// The address the reader is listening on, which is useful when binding to port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the reader is shut down, after which the reader of every
+    /// connection stops as well
+    async fn accept(
+        listener: TcpListener,
+        sender: mpsc::Sender<Submission>,
+        buffer_size: usize,
+        rounding: Rounding,
+        mut shutdown: ShutdownSignal,
+    ) {
+        loop {
+            let result = tokio::select! {
+                result = listener.accept() => result,
+                _ = shutdown.recv() => break,
+            };
+
+            // A failure to accept one connection doesn't affect any other, so it is only logged
+            let stream = match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Stop accepting connections once the receiver has been dropped
+            if sender.is_closed() {
+                break;
+            }
+
+            let (read_half, write_half) = stream.into_split();
+            let (reply, replies) = mpsc::channel(buffer_size);
+            tokio::spawn(
+                CsvReader::with_sender(read_half, sender.clone(), reply, rounding)
+                    .read(shutdown.clone()),
+            );
+            tokio::spawn(Self::acknowledge(write_half, replies));
+        }
+    }
+
+    /// Write every [`Acknowledgement`] of a connection back to it as a CSV record without a header.
+    /// The connection is closed for writing once its last transaction has been acknowledged
+    async fn acknowledge(stream: OwnedWriteHalf, mut replies: mpsc::Receiver<Acknowledgement>) {
+        let mut writer = csv_async::AsyncWriterBuilder::new()
+            .has_headers(false)
+            .create_serializer(stream);
+
+        while let Some(acknowledgement) = replies.recv().await {
+            // Flush every acknowledgement such that the client isn't kept waiting on a buffer. Stop
+            // if the client has gone away, which closes the channel and frees up its reader
+            if writer.serialize(acknowledgement).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl TransactionReader for TcpReader {
+    fn start(self) -> (mpsc::Receiver<Submission>, Shutdown) {
+        let (shutdown, signal) = Shutdown::new();
+
+        tokio::spawn(Self::accept(
+            self.listener,
+            self.sender,
+            self.buffer_size,
+            self.rounding,
+            signal,
+        ));
+
+        (self.receiver, shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::{
+        db_layer::hashmap::HashMapDb, transaction_processing, DisputeState, TransactionType,
+    };
+
+    #[tokio::test]
+    async fn concurrent_connections() {
+        let reader = TcpReader::bind("127.0.0.1:0", 2, Rounding::Reject)
+            .await
+            .unwrap();
+        let addr = reader.local_addr().unwrap();
+        let (mut receiver, _) = reader.start();
+
+        // One connection sends a complete CSV file while the other sends bare records
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let (first_result, second_result) = tokio::join!(
+            first.write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 3, 2.0\n"),
+            second.write_all(b"deposit, 2, 2, 2.0\nwithdrawal, 2, 4, 1.5\ndeposit, x, 5, 1.0\n"),
+        );
+        first_result.unwrap();
+        second_result.unwrap();
+        drop(first);
+        drop(second);
+
+        let mut actual = Vec::new();
+        let mut errors = Vec::new();
+        while actual.len() + errors.len() < 5 {
+            match receiver.recv().await.unwrap().transaction {
+                Ok(transaction) => actual.push(transaction),
+                Err(e) => errors.push(e),
+            }
+        }
+        actual.sort_by_key(|transaction| transaction.tx);
+
+        let deposit = |client, tx, amount| Transaction {
+            ty: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some(amount),
+            state: DisputeState::Normal,
+        };
+        let expected = vec![
+            deposit(1, 1, 10000),
+            deposit(2, 2, 20000),
+            deposit(1, 3, 20000),
+            Transaction {
+                ty: TransactionType::Withdrawal,
+                ..deposit(2, 4, 15000)
+            },
+        ];
+
+        assert_eq!(expected, actual);
+        assert_eq!(1, errors.len());
+        assert_eq!(3, errors[0].line);
+        assert_eq!("deposit,x,5,1.0", errors[0].record);
+    }
+
+    #[tokio::test]
+    async fn acknowledgements() {
+        let reader = TcpReader::bind("127.0.0.1:0", 2, Rounding::Reject)
+            .await
+            .unwrap();
+        let addr = reader.local_addr().unwrap();
+        let (mut receiver, _) = reader.start();
+
+        // Process every transaction the way the binary does, acknowledging each one
+        tokio::spawn(async move {
+            let mut db_layer = HashMapDb::new(2);
+            let policy = transaction_processing::Policy::default();
+            while let Some(Submission {
+                transaction, reply, ..
+            }) = receiver.recv().await
+            {
+                let reply = reply.unwrap();
+                let acknowledgement = match transaction {
+                    Ok(transaction) => {
+                        match transaction_processing::process_transaction(
+                            &mut db_layer,
+                            &policy,
+                            transaction,
+                        )
+                        .await
+                        {
+                            Ok(()) => Acknowledgement::accepted(reply.line, transaction.tx),
+                            Err(e) => Acknowledgement::rejected(reply.line, transaction.tx, &e),
+                        }
+                    }
+                    Err(e) => Acknowledgement::malformed(reply.line, e.to_string()),
+                };
+                reply.send(acknowledgement);
+            }
+        });
+
+        // The header line counts towards the line of every acknowledgement
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"type, client, tx, amount\n")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"deposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0\ndeposit, x, 3, 1.0\n")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut actual = String::new();
+        stream.read_to_string(&mut actual).await.unwrap();
+
+        let lines = actual.lines().collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        assert_eq!("accepted,2,1,,", lines[0]);
+        assert_eq!(
+            "rejected,3,2,InsufficientFunds,the withdrawal exceeds the available funds of the client",
+            lines[1]
+        );
+        assert!(
+            lines[2].starts_with("malformed,4,,,\"malformed record on line 4 (deposit,x,3,1.0)")
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let reader = TcpReader::bind("127.0.0.1:0", 2, Rounding::Reject)
+            .await
+            .unwrap();
+        let addr = reader.local_addr().unwrap();
+        let (mut receiver, shutdown) = reader.start();
+
+        // The connection stays open, so only the shutdown can close the receiver
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"deposit, 1, 1, 1.0\n").await.unwrap();
+        let transaction = receiver.recv().await.unwrap().transaction.unwrap();
+        assert_eq!(1, transaction.tx);
+
+        shutdown.shutdown();
+        assert!(receiver.recv().await.is_none());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
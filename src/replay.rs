@@ -0,0 +1,210 @@
+use serde::Serialize;
+
+use crate::{
+    db_layer::{hashmap::HashMapDb, ClientOrder, DbLayer},
+    transaction_processing::{self, Policy},
+    Client, Error, HumanReadableClient, Transaction,
+};
+
+/// A client whose stored state differs from the state rebuilt from the transaction log. A client
+/// missing on either side is None
+#[derive(Debug, PartialEq, Clone)]
+pub struct Difference {
+    pub client: u16,
+    pub stored: Option<Client>,
+    pub replayed: Option<Client>,
+}
+
+/// The same as [`Difference`], but with every amount as a decimal string
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct HumanReadableDifference {
+    pub client: u16,
+    pub stored: Option<HumanReadableClient>,
+    pub replayed: Option<HumanReadableClient>,
+}
+
+impl From<Difference> for HumanReadableDifference {
+    fn from(difference: Difference) -> HumanReadableDifference {
+        HumanReadableDifference {
+            client: difference.client,
+            stored: difference.stored.map(HumanReadableClient::from),
+            replayed: difference.replayed.map(HumanReadableClient::from),
+        }
+    }
+}
+
+/// Rebuild every client from nothing but a log of transactions by processing each of them in
+/// order against a fresh [`HashMapDb`]. Refused transactions are refused again and left out, but a
+/// failing log or `DbLayer` stops the replay
+pub async fn rebuild(
+    log: impl IntoIterator<Item = Result<Transaction, Error>>,
+    policy: &Policy,
+    buffer_size: usize,
+) -> Result<HashMapDb, Error> {
+    let mut db_layer = HashMapDb::new(buffer_size);
+    for transaction in log {
+        match transaction_processing::process_transaction(&mut db_layer, policy, transaction?).await
+        {
+            Ok(()) => {}
+            Err(e @ Error::DbLayer(_)) => return Err(e),
+            Err(_) => {}
+        }
+    }
+    Ok(db_layer)
+}
+
+/// Every client whose state differs between the stored and the replayed [`DbLayer`], ordered by
+/// client ID
+pub async fn differences(
+    stored: impl DbLayer,
+    replayed: impl DbLayer,
+) -> Result<Vec<Difference>, Error> {
+    let mut stored = stored.stream_clients(ClientOrder::Id).await;
+    let mut replayed = replayed.stream_clients(ClientOrder::Id).await;
+
+    // Both sides stream their clients by ascending ID, so they are merged like sorted lists
+    let mut differences = Vec::new();
+    let mut next_stored = stored.recv().await.transpose()?;
+    let mut next_replayed = replayed.recv().await.transpose()?;
+    loop {
+        let (client, take_stored, take_replayed) = match (&next_stored, &next_replayed) {
+            (None, None) => break,
+            (Some(a), Some(b)) if a.client == b.client => (a.client, true, true),
+            (Some(a), Some(b)) if a.client < b.client => (a.client, true, false),
+            (Some(a), None) => (a.client, true, false),
+            (_, Some(b)) => (b.client, false, true),
+        };
+
+        let difference = Difference {
+            client,
+            stored: if take_stored {
+                next_stored.take()
+            } else {
+                None
+            },
+            replayed: if take_replayed {
+                next_replayed.take()
+            } else {
+                None
+            },
+        };
+        if difference.stored != difference.replayed {
+            differences.push(difference);
+        }
+
+        if take_stored {
+            next_stored = stored.recv().await.transpose()?;
+        }
+        if take_replayed {
+            next_replayed = replayed.recv().await.transpose()?;
+        }
+    }
+
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{db_layer::sled_db::SledDb, db_layer::WriteBatch, DisputeState, TransactionType};
+
+    fn transaction(ty: TransactionType, client: u16, tx: u32, amount: Option<i64>) -> Transaction {
+        Transaction {
+            ty,
+            client,
+            tx,
+            amount,
+            state: DisputeState::Normal,
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_matches_stored_clients() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+        let policy = Policy::default();
+
+        let input = vec![
+            transaction(TransactionType::Deposit, 1, 1, Some(30000)),
+            transaction(TransactionType::Deposit, 2, 2, Some(20000)),
+            transaction(TransactionType::Withdrawal, 1, 3, Some(50000)),
+            transaction(TransactionType::Withdrawal, 1, 4, Some(10000)),
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::Chargeback, 2, 2, None),
+            transaction(TransactionType::Deposit, 3, 2, Some(10000)),
+        ];
+        for transaction in input.iter() {
+            let _ =
+                transaction_processing::process_transaction(&mut db_layer, &policy, *transaction)
+                    .await;
+        }
+
+        // Every transaction is logged in order, including the refused ones
+        let log = db_layer
+            .logged_transactions()
+            .unwrap()
+            .collect::<Result<Vec<_>, Error>>()
+            .unwrap();
+        assert_eq!(input, log);
+
+        let replayed = rebuild(log.clone().into_iter().map(Ok), &policy, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            Vec::<Difference>::new(),
+            differences(db_layer, replayed).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_clients_are_reported() {
+        let dir = TempDir::new_in("./").unwrap();
+        let mut db_layer = SledDb::new(dir.path().join("database"), 2).unwrap();
+        let policy = Policy::default();
+
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, Some(30000)),
+            transaction(TransactionType::Deposit, 2, 2, Some(20000)),
+        ] {
+            transaction_processing::process_transaction(&mut db_layer, &policy, transaction)
+                .await
+                .unwrap();
+        }
+        let log = db_layer.logged_transactions().unwrap().collect::<Vec<_>>();
+
+        // Client 2 is corrupted and client 3 was never part of the log
+        let client = |client, total| Client {
+            client,
+            available: total,
+            held: 0,
+            total,
+            locked: false,
+        };
+        db_layer
+            .write_batch(WriteBatch {
+                clients: vec![client(2, 99999), client(3, 10000)],
+                ..WriteBatch::default()
+            })
+            .await
+            .unwrap();
+
+        let replayed = rebuild(log, &policy, 2).await.unwrap();
+        assert_eq!(
+            vec![
+                Difference {
+                    client: 2,
+                    stored: Some(client(2, 99999)),
+                    replayed: Some(client(2, 20000)),
+                },
+                Difference {
+                    client: 3,
+                    stored: Some(client(3, 10000)),
+                    replayed: None,
+                },
+            ],
+            differences(db_layer, replayed).await.unwrap()
+        );
+    }
+}
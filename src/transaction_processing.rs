@@ -1,12 +1,119 @@
+use serde::{Deserialize, Serialize};
+
 use super::*;
 
-/// Process a single transaction
+/// The rules applied by [`process_transaction`] where there is more than one reasonable choice
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Policy {
+    /// Whether Disputes, Resolves, and Chargebacks are still processed for a locked client. Deposits
+    /// and Withdrawals are always refused for a locked client
+    pub disputes_on_locked: bool,
+
+    /// Whether a transaction whose dispute has been resolved may be disputed again. A transaction
+    /// which has been charged back may never be disputed again
+    pub redispute_resolved: bool,
+
+    /// How Disputes referencing a Withdrawal are handled
+    pub withdrawal_disputes: WithdrawalDisputes,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            disputes_on_locked: true,
+            redispute_resolved: false,
+            withdrawal_disputes: WithdrawalDisputes::Reverse,
+        }
+    }
+}
+
+/// The ways in which a Dispute referencing a Withdrawal may be handled
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WithdrawalDisputes {
+    /// The disputed amount is credited back to the client as held funds. A Resolve lets the
+    /// Withdrawal stand, removing the held funds, while a Chargeback reverses the Withdrawal,
+    /// releasing the held funds to the client's available funds
+    Reverse,
+    /// Disputes referencing a Withdrawal are refused with [`Error::WithdrawalNotDisputable`]
+    Reject,
+}
+
+impl std::str::FromStr for WithdrawalDisputes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WithdrawalDisputes, String> {
+        match s {
+            "reverse" => Ok(WithdrawalDisputes::Reverse),
+            "reject" => Ok(WithdrawalDisputes::Reject),
+            other => Err(format!(
+                "unknown withdrawal dispute policy `{}`, expected one of `reverse`, `reject`",
+                other
+            )),
+        }
+    }
+}
+
+/// Process a single transaction, recording it in the audit log of its client whether it is applied
+/// or refused
 pub async fn process_transaction(
     db: &mut impl db_layer::DbLayer,
+    policy: &Policy,
     transaction: Transaction,
 ) -> Result<(), Error> {
+    process(db, policy, transaction, None).await
+}
+
+/// Process a single transaction like [`process_transaction`], storing where it was read from as
+/// the cursor of its client in the same write whether it is applied or refused
+pub async fn process_ingested(
+    db: &mut impl db_layer::DbLayer,
+    policy: &Policy,
+    transaction: Transaction,
+    cursor: Cursor,
+) -> Result<(), Error> {
+    process(db, policy, transaction, Some(cursor)).await
+}
+
+/// Refuse a transaction with an error found without processing it, such as a transaction ID
+/// already taken by a client held elsewhere, recording it and its cursor as [`process_ingested`]
+/// does for any transaction it refuses
+pub async fn refuse_ingested(
+    db: &mut impl db_layer::DbLayer,
+    transaction: Transaction,
+    cursor: Cursor,
+    error: Error,
+) -> Result<(), Error> {
+    let entry = audit_entry(db, transaction).await?;
+    refuse(db, entry, Some((transaction.client, cursor)), error).await
+}
+
+async fn process(
+    db: &mut impl db_layer::DbLayer,
+    policy: &Policy,
+    transaction: Transaction,
+    cursor: Option<Cursor>,
+) -> Result<(), Error> {
+    let cursor = cursor.map(|cursor| (transaction.client, cursor));
+    let mut entry = audit_entry(db, transaction).await?;
+
+    match apply(db, policy, transaction, entry.before).await {
+        Ok(mut batch) => {
+            entry.after = batch.clients[0];
+            batch.audit.push(entry);
+            batch.cursor = cursor;
+            db.write_batch(batch).await
+        }
+        Err(e) => refuse(db, entry, cursor, e).await,
+    }
+}
+
+/// The audit entry of a transaction before it is applied, with the client as it currently is
+async fn audit_entry(
+    db: &mut impl db_layer::DbLayer,
+    transaction: Transaction,
+) -> Result<AuditEntry, Error> {
     // If there is already a client with that ID, modify it
-    let mut client = if let Some(client) = db.get_client(transaction.client).await? {
+    let before = if let Some(client) = db.get_client(transaction.client).await? {
         client
     } else {
         Client {
@@ -18,50 +125,120 @@ pub async fn process_transaction(
         }
     };
 
+    Ok(AuditEntry {
+        id: 0,
+        timestamp: now(),
+        transaction,
+        error: None,
+        before,
+        after: before,
+    })
+}
+
+/// Record a refused transaction in the audit log of its client along with its cursor, returning
+/// the error it was refused with
+async fn refuse(
+    db: &mut impl db_layer::DbLayer,
+    mut entry: AuditEntry,
+    cursor: Option<(u16, Cursor)>,
+    error: Error,
+) -> Result<(), Error> {
+    // A failing DbLayer would most likely fail to record the refusal as well
+    if let Error::DbLayer(_) = error {
+        return Err(error);
+    }
+
+    entry.error = Some(error.variant().to_owned());
+    let batch = db_layer::WriteBatch {
+        audit: vec![entry],
+        cursor,
+        ..db_layer::WriteBatch::default()
+    };
+    db.write_batch(batch).await?;
+    Err(error)
+}
+
+/// The current time in milliseconds since the Unix epoch
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Apply a transaction to its client, returning every write needed to store the result, the
+/// updated client being the first client of the batch
+async fn apply(
+    db: &mut impl db_layer::DbLayer,
+    policy: &Policy,
+    transaction: Transaction,
+    mut client: Client,
+) -> Result<db_layer::WriteBatch, Error> {
+    // A client is frozen once locked, though disputes already underway may be allowed to finish
+    if client.locked {
+        let is_dispute = !matches!(
+            transaction.ty,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        if !(is_dispute && policy.disputes_on_locked) {
+            return Err(Error::AccountLocked);
+        }
+    }
+
+    // Deposits and withdrawals are only ever applied once per transaction ID such that resent
+    // transactions don't credit or debit a client twice
+    if matches!(
+        transaction.ty,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    ) && db.get_transaction(transaction.tx).await?.is_some()
+    {
+        return Err(Error::DuplicateTransaction);
+    }
+
+    // Every write is collected such that the transaction is applied all at once or not at all
+    let mut batch = db_layer::WriteBatch::default();
+
     match transaction.ty {
         TransactionType::Deposit => {
             process_deposit(&mut client, transaction)?;
-            db.write_transaction(transaction).await?;
+            batch.transactions.push(transaction);
         }
 
         TransactionType::Withdrawal => {
             process_withdrawal(&mut client, transaction)?;
-            db.write_transaction(transaction).await?;
+            batch.transactions.push(transaction);
         }
 
         TransactionType::Dispute => {
             let mut referenced_transaction = db.get_transaction(transaction.tx).await?;
-            process_dispute(&mut client, &mut referenced_transaction)?;
-            if let Some(referenced_transaction) = referenced_transaction {
-                db.write_transaction(referenced_transaction).await?;
-            }
+            process_dispute(&mut client, &mut referenced_transaction, policy)?;
+            batch.transactions.extend(referenced_transaction);
         }
 
         TransactionType::Resolve => {
             let mut referenced_transaction = db.get_transaction(transaction.tx).await?;
-            process_resolve(&mut client, &mut referenced_transaction)?;
-            if let Some(referenced_transaction) = referenced_transaction {
-                db.write_transaction(referenced_transaction).await?;
-            }
+            process_resolve(&mut client, &mut referenced_transaction, policy)?;
+            batch.transactions.extend(referenced_transaction);
         }
 
         TransactionType::Chargeback => {
             let mut referenced_transaction = db.get_transaction(transaction.tx).await?;
-            process_chargeback(&mut client, &mut referenced_transaction)?;
-            if let Some(referenced_transaction) = referenced_transaction {
-                db.write_transaction(referenced_transaction).await?;
-            }
+            process_chargeback(&mut client, &mut referenced_transaction, policy)?;
+            batch.transactions.extend(referenced_transaction);
         }
     }
 
-    db.write_client(client).await?;
-    Ok(())
+    batch.clients.push(client);
+    Ok(batch)
 }
 
 fn process_deposit(client: &mut Client, transaction: Transaction) -> Result<(), Error> {
     if let Some(amount) = transaction.amount {
-        client.available += amount;
-        client.total += amount;
+        if amount <= 0 {
+            return Err(Error::NonPositiveAmount);
+        }
+        client.available = add(client.available, amount)?;
+        client.total = add(client.total, amount)?;
         Ok(())
     } else {
         Err(Error::NoAmount)
@@ -70,9 +247,13 @@ fn process_deposit(client: &mut Client, transaction: Transaction) -> Result<(),
 
 fn process_withdrawal(client: &mut Client, transaction: Transaction) -> Result<(), Error> {
     if let Some(amount) = transaction.amount {
-        if client.available - amount >= 0 {
-            client.available -= amount;
-            client.total -= amount;
+        // A negative withdrawal would otherwise credit the client
+        if amount <= 0 {
+            return Err(Error::NonPositiveAmount);
+        }
+        if client.available >= amount {
+            client.available = sub(client.available, amount)?;
+            client.total = sub(client.total, amount)?;
             Ok(())
         } else {
             Err(Error::InsufficientFunds)
@@ -82,17 +263,65 @@ fn process_withdrawal(client: &mut Client, transaction: Transaction) -> Result<(
     }
 }
 
+/// Add an amount to a balance, refusing the transaction if the result doesn't fit in an i64
+fn add(balance: i64, amount: i64) -> Result<i64, Error> {
+    balance.checked_add(amount).ok_or(Error::Overflow)
+}
+
+/// Subtract an amount from a balance, refusing the transaction if the result doesn't fit in an i64
+fn sub(balance: i64, amount: i64) -> Result<i64, Error> {
+    balance.checked_sub(amount).ok_or(Error::Overflow)
+}
+
+/// Find the [`DisputeState`] a referenced transaction moves to when a Dispute, Resolve, or
+/// Chargeback of the given type is applied to it, refusing any transition which isn't legal
+fn next_state(
+    state: DisputeState,
+    ty: TransactionType,
+    policy: &Policy,
+) -> Result<DisputeState, Error> {
+    match (ty, state) {
+        (TransactionType::Dispute, DisputeState::Normal) => Ok(DisputeState::Disputed),
+        (TransactionType::Dispute, DisputeState::Resolved) if policy.redispute_resolved => {
+            Ok(DisputeState::Disputed)
+        }
+        (TransactionType::Dispute, DisputeState::Resolved) => Err(Error::AlreadyResolved),
+        (TransactionType::Dispute, DisputeState::Disputed) => Err(Error::AlreadyDisputed),
+        (TransactionType::Dispute, DisputeState::ChargedBack) => Err(Error::AlreadyChargedBack),
+        (TransactionType::Resolve, DisputeState::Disputed) => Ok(DisputeState::Resolved),
+        (TransactionType::Chargeback, DisputeState::Disputed) => Ok(DisputeState::ChargedBack),
+        _ => Err(Error::NotDisputed),
+    }
+}
+
 fn process_dispute(
     client: &mut Client,
     referenced_transaction: &mut Option<Transaction>,
+    policy: &Policy,
 ) -> Result<(), Error> {
-    // TODO: Check that the transaction hasn't already been disputed
-    if let Some(mut referenced_transaction) = referenced_transaction.as_mut() {
+    if let Some(referenced_transaction) = referenced_transaction.as_mut() {
         if referenced_transaction.client == client.client {
             if let Some(amount) = referenced_transaction.amount {
-                client.available -= amount;
-                client.held += amount;
-                referenced_transaction.disputed = true;
+                let is_withdrawal = referenced_transaction.ty == TransactionType::Withdrawal;
+                if is_withdrawal && policy.withdrawal_disputes == WithdrawalDisputes::Reject {
+                    return Err(Error::WithdrawalNotDisputable);
+                }
+
+                referenced_transaction.state = next_state(
+                    referenced_transaction.state,
+                    TransactionType::Dispute,
+                    policy,
+                )?;
+
+                // The funds of a deposit are moved from available to held while the funds of a
+                // withdrawal, having already left the account, are returned as held
+                if is_withdrawal {
+                    client.held = add(client.held, amount)?;
+                    client.total = add(client.total, amount)?;
+                } else {
+                    client.available = sub(client.available, amount)?;
+                    client.held = add(client.held, amount)?;
+                }
                 Ok(())
             } else {
                 Err(Error::NoAmount)
@@ -108,20 +337,28 @@ fn process_dispute(
 fn process_resolve(
     client: &mut Client,
     referenced_transaction: &mut Option<Transaction>,
+    policy: &Policy,
 ) -> Result<(), Error> {
-    if let Some(mut referenced_transaction) = referenced_transaction.as_mut() {
+    if let Some(referenced_transaction) = referenced_transaction.as_mut() {
         if referenced_transaction.client == client.client {
-            if referenced_transaction.disputed {
-                if let Some(amount) = referenced_transaction.amount {
-                    client.available += amount;
-                    client.held -= amount;
-                    referenced_transaction.disputed = false;
-                    Ok(())
+            if let Some(amount) = referenced_transaction.amount {
+                referenced_transaction.state = next_state(
+                    referenced_transaction.state,
+                    TransactionType::Resolve,
+                    policy,
+                )?;
+
+                // Resolving the dispute of a withdrawal lets the withdrawal stand
+                if referenced_transaction.ty == TransactionType::Withdrawal {
+                    client.held = sub(client.held, amount)?;
+                    client.total = sub(client.total, amount)?;
                 } else {
-                    Err(Error::NoAmount)
+                    client.available = add(client.available, amount)?;
+                    client.held = sub(client.held, amount)?;
                 }
+                Ok(())
             } else {
-                Err(Error::NotDisputed)
+                Err(Error::NoAmount)
             }
         } else {
             Err(Error::ReferencesWrongClient)
@@ -134,21 +371,29 @@ fn process_resolve(
 fn process_chargeback(
     client: &mut Client,
     referenced_transaction: &mut Option<Transaction>,
+    policy: &Policy,
 ) -> Result<(), Error> {
-    if let Some(mut referenced_transaction) = referenced_transaction.as_mut() {
+    if let Some(referenced_transaction) = referenced_transaction.as_mut() {
         if referenced_transaction.client == client.client {
-            if referenced_transaction.disputed {
-                if let Some(amount) = referenced_transaction.amount {
-                    client.held -= amount;
-                    client.total -= amount;
-                    client.locked = true;
-                    referenced_transaction.disputed = false;
-                    Ok(())
+            if let Some(amount) = referenced_transaction.amount {
+                referenced_transaction.state = next_state(
+                    referenced_transaction.state,
+                    TransactionType::Chargeback,
+                    policy,
+                )?;
+
+                // Charging back a withdrawal reverses it, returning the funds to the client
+                if referenced_transaction.ty == TransactionType::Withdrawal {
+                    client.held = sub(client.held, amount)?;
+                    client.available = add(client.available, amount)?;
                 } else {
-                    Err(Error::NoAmount)
+                    client.held = sub(client.held, amount)?;
+                    client.total = sub(client.total, amount)?;
                 }
+                client.locked = true;
+                Ok(())
             } else {
-                Err(Error::NotDisputed)
+                Err(Error::NoAmount)
             }
         } else {
             Err(Error::ReferencesWrongClient)
@@ -169,69 +414,577 @@ mod tests {
                 client: 1,
                 tx: 1,
                 amount: Some(10000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 2,
                 tx: 2,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Deposit,
                 client: 1,
                 tx: 3,
                 amount: Some(20000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 1,
                 tx: 4,
                 amount: Some(15000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
             Transaction {
                 ty: TransactionType::Withdrawal,
                 client: 2,
                 tx: 5,
                 amount: Some(30000),
-                disputed: false,
+                state: DisputeState::Normal,
             },
         ];
 
         let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
 
         for input in inputs {
-            let _ = process_transaction(&mut db_layer, input).await;
+            let _ = process_transaction(&mut db_layer, &Policy::default(), input).await;
+        }
+
+        let expected = vec![
+            Client {
+                client: 1,
+                available: 15000,
+                held: 0,
+                total: 15000,
+                locked: false,
+            },
+            Client {
+                client: 2,
+                available: 20000,
+                held: 0,
+                total: 20000,
+                locked: false,
+            },
+        ];
+
+        let mut receiver = db_layer.stream_clients(db_layer::ClientOrder::Id).await;
+        let mut actual = Vec::new();
+        while let Some(client) = receiver.recv().await {
+            actual.push(client.unwrap());
+        }
+
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn duplicate_transactions() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let dispute = Transaction {
+            ty: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: None,
+            state: DisputeState::Normal,
+        };
+        let withdrawal = Transaction {
+            ty: TransactionType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some(5000),
+            state: DisputeState::Normal,
+        };
+
+        let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+
+        process_transaction(&mut db_layer, &Policy::default(), deposit)
+            .await
+            .unwrap();
+        process_transaction(&mut db_layer, &Policy::default(), dispute)
+            .await
+            .unwrap();
+        assert!(matches!(
+            process_transaction(&mut db_layer, &Policy::default(), deposit).await,
+            Err(Error::DuplicateTransaction)
+        ));
+        assert!(matches!(
+            process_transaction(&mut db_layer, &Policy::default(), withdrawal).await,
+            Err(Error::DuplicateTransaction)
+        ));
+
+        let expected_client = Client {
+            client: 1,
+            available: 0,
+            held: 10000,
+            total: 10000,
+            locked: false,
+        };
+        let expected_transaction = Transaction {
+            state: DisputeState::Disputed,
+            ..deposit
+        };
+
+        assert_eq!(Some(expected_client), db_layer.get_client(1).await.unwrap());
+        assert_eq!(
+            Some(expected_transaction),
+            db_layer.get_transaction(1).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn non_positive_amounts() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let negative_deposit = Transaction {
+            tx: 2,
+            amount: Some(-5000),
+            ..deposit
+        };
+        let zero_deposit = Transaction {
+            tx: 3,
+            amount: Some(0),
+            ..deposit
+        };
+        let negative_withdrawal = Transaction {
+            ty: TransactionType::Withdrawal,
+            tx: 4,
+            amount: Some(-5000),
+            ..deposit
+        };
+
+        let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+
+        process_transaction(&mut db_layer, &Policy::default(), deposit)
+            .await
+            .unwrap();
+        for transaction in [negative_deposit, zero_deposit, negative_withdrawal] {
+            assert!(matches!(
+                process_transaction(&mut db_layer, &Policy::default(), transaction).await,
+                Err(Error::NonPositiveAmount)
+            ));
         }
 
-        let client_1 = Client {
+        let expected_client = Client {
             client: 1,
-            available: 15000,
+            available: 10000,
             held: 0,
-            total: 15000,
+            total: 10000,
             locked: false,
         };
+        assert_eq!(Some(expected_client), db_layer.get_client(1).await.unwrap());
+    }
 
-        let client_2 = Client {
-            client: 2,
+    #[tokio::test]
+    async fn overflowing_balances() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(i64::MAX),
+            state: DisputeState::Normal,
+        };
+        let overflowing_deposit = Transaction {
+            tx: 2,
+            amount: Some(1),
+            ..deposit
+        };
+
+        let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+
+        process_transaction(&mut db_layer, &Policy::default(), deposit)
+            .await
+            .unwrap();
+        assert!(matches!(
+            process_transaction(&mut db_layer, &Policy::default(), overflowing_deposit).await,
+            Err(Error::Overflow)
+        ));
+
+        let expected_client = Client {
+            client: 1,
+            available: i64::MAX,
+            held: 0,
+            total: i64::MAX,
+            locked: false,
+        };
+        assert_eq!(Some(expected_client), db_layer.get_client(1).await.unwrap());
+        assert_eq!(None, db_layer.get_transaction(2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn locked_accounts() {
+        let inputs = [
+            Transaction {
+                ty: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(10000),
+                state: DisputeState::Normal,
+            },
+            Transaction {
+                ty: TransactionType::Deposit,
+                client: 1,
+                tx: 2,
+                amount: Some(20000),
+                state: DisputeState::Normal,
+            },
+            Transaction {
+                ty: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                state: DisputeState::Normal,
+            },
+            Transaction {
+                ty: TransactionType::Dispute,
+                client: 1,
+                tx: 2,
+                amount: None,
+                state: DisputeState::Normal,
+            },
+            Transaction {
+                ty: TransactionType::Chargeback,
+                client: 1,
+                tx: 1,
+                amount: None,
+                state: DisputeState::Normal,
+            },
+        ];
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 3,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let withdrawal = Transaction {
+            ty: TransactionType::Withdrawal,
+            client: 1,
+            tx: 4,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let resolve = Transaction {
+            ty: TransactionType::Resolve,
+            client: 1,
+            tx: 2,
+            amount: None,
+            state: DisputeState::Normal,
+        };
+
+        for disputes_on_locked in [true, false] {
+            let policy = Policy {
+                disputes_on_locked,
+                ..Policy::default()
+            };
+            let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+
+            for input in inputs.iter() {
+                process_transaction(&mut db_layer, &policy, *input)
+                    .await
+                    .unwrap();
+            }
+
+            assert!(matches!(
+                process_transaction(&mut db_layer, &policy, deposit).await,
+                Err(Error::AccountLocked)
+            ));
+            assert!(matches!(
+                process_transaction(&mut db_layer, &policy, withdrawal).await,
+                Err(Error::AccountLocked)
+            ));
+
+            let resolved = process_transaction(&mut db_layer, &policy, resolve).await;
+            let expected = if disputes_on_locked {
+                assert!(resolved.is_ok());
+                Client {
+                    client: 1,
+                    available: 20000,
+                    held: 0,
+                    total: 20000,
+                    locked: true,
+                }
+            } else {
+                assert!(matches!(resolved, Err(Error::AccountLocked)));
+                Client {
+                    client: 1,
+                    available: 0,
+                    held: 20000,
+                    total: 20000,
+                    locked: true,
+                }
+            };
+
+            assert_eq!(Some(expected), db_layer.get_client(1).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn dispute_states() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let dispute = Transaction {
+            ty: TransactionType::Dispute,
+            amount: None,
+            ..deposit
+        };
+        let resolve = Transaction {
+            ty: TransactionType::Resolve,
+            ..dispute
+        };
+        let chargeback = Transaction {
+            ty: TransactionType::Chargeback,
+            ..dispute
+        };
+
+        for redispute_resolved in [true, false] {
+            let policy = Policy {
+                redispute_resolved,
+                ..Policy::default()
+            };
+            let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+
+            process_transaction(&mut db_layer, &policy, deposit)
+                .await
+                .unwrap();
+            process_transaction(&mut db_layer, &policy, dispute)
+                .await
+                .unwrap();
+            assert!(matches!(
+                process_transaction(&mut db_layer, &policy, dispute).await,
+                Err(Error::AlreadyDisputed)
+            ));
+            process_transaction(&mut db_layer, &policy, resolve)
+                .await
+                .unwrap();
+            assert!(matches!(
+                process_transaction(&mut db_layer, &policy, resolve).await,
+                Err(Error::NotDisputed)
+            ));
+
+            let redisputed = process_transaction(&mut db_layer, &policy, dispute).await;
+            if !redispute_resolved {
+                assert!(matches!(redisputed, Err(Error::AlreadyResolved)));
+                assert_eq!(
+                    DisputeState::Resolved,
+                    db_layer.get_transaction(1).await.unwrap().unwrap().state
+                );
+                continue;
+            }
+
+            assert!(redisputed.is_ok());
+            process_transaction(&mut db_layer, &policy, chargeback)
+                .await
+                .unwrap();
+            assert!(matches!(
+                process_transaction(&mut db_layer, &policy, dispute).await,
+                Err(Error::AlreadyChargedBack)
+            ));
+
+            let expected = Client {
+                client: 1,
+                available: 0,
+                held: 0,
+                total: 0,
+                locked: true,
+            };
+            assert_eq!(Some(expected), db_layer.get_client(1).await.unwrap());
+            assert_eq!(
+                DisputeState::ChargedBack,
+                db_layer.get_transaction(1).await.unwrap().unwrap().state
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn withdrawal_disputes() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(30000),
+            state: DisputeState::Normal,
+        };
+        let withdrawal = Transaction {
+            ty: TransactionType::Withdrawal,
+            tx: 2,
+            amount: Some(10000),
+            ..deposit
+        };
+        let dispute = Transaction {
+            ty: TransactionType::Dispute,
+            amount: None,
+            ..withdrawal
+        };
+        let resolve = Transaction {
+            ty: TransactionType::Resolve,
+            ..dispute
+        };
+        let chargeback = Transaction {
+            ty: TransactionType::Chargeback,
+            ..dispute
+        };
+
+        let policy = Policy {
+            redispute_resolved: true,
+            ..Policy::default()
+        };
+        let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+        for input in [deposit, withdrawal, dispute] {
+            process_transaction(&mut db_layer, &policy, input)
+                .await
+                .unwrap();
+        }
+
+        let disputed = Client {
+            client: 1,
             available: 20000,
+            held: 10000,
+            total: 30000,
+            locked: false,
+        };
+        assert_eq!(Some(disputed), db_layer.get_client(1).await.unwrap());
+
+        // Resolving lets the withdrawal stand
+        process_transaction(&mut db_layer, &policy, resolve)
+            .await
+            .unwrap();
+        let resolved = Client {
             held: 0,
             total: 20000,
+            ..disputed
+        };
+        assert_eq!(Some(resolved), db_layer.get_client(1).await.unwrap());
+
+        // Charging back reverses the withdrawal
+        for input in [dispute, chargeback] {
+            process_transaction(&mut db_layer, &policy, input)
+                .await
+                .unwrap();
+        }
+        let charged_back = Client {
+            client: 1,
+            available: 30000,
+            held: 0,
+            total: 30000,
+            locked: true,
+        };
+        assert_eq!(Some(charged_back), db_layer.get_client(1).await.unwrap());
+
+        let policy = Policy {
+            withdrawal_disputes: WithdrawalDisputes::Reject,
+            ..Policy::default()
+        };
+        let mut db_layer = db_layer::hashmap::HashMapDb::new(2);
+        for input in [deposit, withdrawal] {
+            process_transaction(&mut db_layer, &policy, input)
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            process_transaction(&mut db_layer, &policy, dispute).await,
+            Err(Error::WithdrawalNotDisputable)
+        ));
+        assert_eq!(
+            DisputeState::Normal,
+            db_layer.get_transaction(2).await.unwrap().unwrap().state
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_log() {
+        let deposit = Transaction {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(10000),
+            state: DisputeState::Normal,
+        };
+        let withdrawal = Transaction {
+            ty: TransactionType::Withdrawal,
+            tx: 2,
+            amount: Some(50000),
+            ..deposit
+        };
+        let dispute = Transaction {
+            ty: TransactionType::Dispute,
+            amount: None,
+            ..deposit
+        };
+        let other_client = Transaction {
+            client: 2,
+            tx: 3,
+            ..deposit
+        };
+
+        let dir = tempfile::TempDir::new_in("./").unwrap();
+        let mut db_layer = db_layer::sled_db::SledDb::new(dir.path().join("database"), 2).unwrap();
+        let policy = Policy::default();
+        for transaction in [deposit, withdrawal, dispute, other_client] {
+            let _ = process_transaction(&mut db_layer, &policy, transaction).await;
+        }
+
+        let empty = Client {
+            client: 1,
+            available: 0,
+            held: 0,
+            total: 0,
             locked: false,
         };
+        let deposited = Client {
+            available: 10000,
+            total: 10000,
+            ..empty
+        };
+        let disputed = Client {
+            available: 0,
+            held: 10000,
+            ..deposited
+        };
 
-        let actual_out = db_layer
-            .stream_clients()
-            .await
-            .recv()
+        let history = db_layer.client_history(1, 0..u64::MAX).await.unwrap();
+        let summary = history
+            .iter()
+            .map(|entry| {
+                (
+                    entry.transaction,
+                    entry.error.as_deref(),
+                    entry.before,
+                    entry.after,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (deposit, None, empty, deposited),
+                (withdrawal, Some("InsufficientFunds"), deposited, deposited),
+                (dispute, None, deposited, disputed),
+            ],
+            summary
+        );
+
+        // Only entries within the range of timestamps are included
+        let first = history[0].timestamp;
+        assert!(db_layer
+            .client_history(1, 0..first)
             .await
             .unwrap()
-            .unwrap();
-        println!("{:?}", actual_out);
-
-        assert!(actual_out == client_1 || actual_out == client_2);
+            .is_empty());
     }
 }
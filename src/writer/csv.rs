@@ -1,29 +1,223 @@
 use async_trait::async_trait;
+use std::path::Path;
+use tokio::io::AsyncWrite;
 
 use super::*;
 
-/// Writes CSV values to stdout
+/// Writes CSV values to stdout, to a file, or to any other [`AsyncWrite`] implementor
 pub struct CsvWriter {
-    writer: csv_async::AsyncSerializer<tokio::io::Stdout>,
+    writer: csv_async::AsyncSerializer<Output>,
 }
 
 impl CsvWriter {
     pub fn new() -> CsvWriter {
-        let writer = csv_async::AsyncSerializer::from_writer(tokio::io::stdout());
-        CsvWriter { writer }
+        Self::from_writer(tokio::io::stdout())
+    }
+
+    pub fn from_writer(writer: impl AsyncWrite + Unpin + Send + 'static) -> CsvWriter {
+        Self::from_output(Output::from_writer(writer))
+    }
+
+    /// Write to the file at the given path, which is replaced all at once when the writer is closed
+    pub async fn create(path: impl AsRef<Path>) -> std::io::Result<CsvWriter> {
+        Ok(Self::from_output(Output::create(path).await?))
+    }
+
+    fn from_output(output: Output) -> CsvWriter {
+        CsvWriter {
+            writer: csv_async::AsyncSerializer::from_writer(output),
+        }
     }
 }
 
 #[async_trait]
 impl ClientWriter for CsvWriter {
-    // FIXME: Eliminate unwrap
     async fn append_client(&mut self, client: Client) -> Result<(), Error> {
         let client: HumanReadableClient = client.into();
-        self.writer.serialize(client).await.unwrap();
+        self.writer.serialize(client).await?;
         Ok(())
     }
 
     async fn close(self) -> Result<(), Error> {
+        commit(self.writer).await
+    }
+}
+
+/// Writes [`Rejection`]s as CSV values to a file or to any other [`AsyncWrite`] implementor
+pub struct CsvRejectionWriter {
+    writer: csv_async::AsyncSerializer<Output>,
+}
+
+impl CsvRejectionWriter {
+    /// Write to the file at the given path, which is replaced all at once when the writer is closed
+    pub async fn new(path: impl AsRef<Path>) -> std::io::Result<CsvRejectionWriter> {
+        Ok(CsvRejectionWriter {
+            writer: csv_async::AsyncSerializer::from_writer(Output::create(path).await?),
+        })
+    }
+
+    /// Append to the file at the given path, see [`Output::append`]. The header line is only
+    /// written to a file which is empty
+    pub async fn append(path: impl AsRef<Path>) -> std::io::Result<CsvRejectionWriter> {
+        let (output, empty) = Output::append(path).await?;
+        Ok(CsvRejectionWriter {
+            writer: csv_async::AsyncWriterBuilder::new()
+                .has_headers(empty)
+                .create_serializer(output),
+        })
+    }
+}
+
+/// Flush everything serialized and commit the [`Output`] it was written to
+async fn commit(writer: csv_async::AsyncSerializer<Output>) -> Result<(), Error> {
+    let output = writer
+        .into_inner()
+        .await
+        .map_err(|e| Error::from(e.into_error()))?;
+    output.commit().await?;
+    Ok(())
+}
+
+impl From<csv_async::Error> for Error {
+    fn from(e: csv_async::Error) -> Error {
+        Error::Writer(format!("{}", e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Writer(format!("{}", e))
+    }
+}
+
+#[async_trait]
+impl RejectionWriter for CsvRejectionWriter {
+    async fn append_rejection(&mut self, rejection: Rejection) -> Result<(), Error> {
+        self.writer.serialize(rejection).await?;
         Ok(())
     }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn close(self) -> Result<(), Error> {
+        commit(self.writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{Client, DisputeState, Transaction, TransactionType};
+
+    #[tokio::test]
+    async fn in_memory() {
+        let (writer, mut reader) = tokio::io::duplex(1024);
+
+        let mut writer = CsvWriter::from_writer(writer);
+        writer
+            .append_client(Client {
+                client: 1,
+                available: 15000,
+                held: 5000,
+                total: 20000,
+                locked: true,
+            })
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        let mut actual = String::new();
+        reader.read_to_string(&mut actual).await.unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5000,0.5000,2.0000,true\n",
+            actual
+        );
+    }
+
+    #[tokio::test]
+    async fn rejection_report() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("rejections.csv");
+
+        let mut writer = CsvRejectionWriter::new(&path).await.unwrap();
+        writer
+            .append_rejection(Rejection::new(
+                Transaction {
+                    ty: TransactionType::Withdrawal,
+                    client: 1,
+                    tx: 4,
+                    amount: Some(15000),
+                    state: DisputeState::Normal,
+                },
+                &Error::InsufficientFunds,
+            ))
+            .await
+            .unwrap();
+        writer
+            .append_rejection(Rejection::new(
+                Transaction {
+                    ty: TransactionType::Resolve,
+                    client: 2,
+                    tx: 2,
+                    amount: None,
+                    state: DisputeState::Normal,
+                },
+                &Error::NotDisputed,
+            ))
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        let expected = "type,client,tx,amount,error,reason\n\
+            withdrawal,1,4,1.5000,InsufficientFunds,the withdrawal exceeds the available funds of the client\n\
+            resolve,2,2,,NotDisputed,the referenced transaction is not disputed\n";
+        let actual = tokio::fs::read_to_string(&path).await.unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn rejections_appended() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("rejections.csv");
+
+        let rejection = |tx| {
+            Rejection::new(
+                Transaction {
+                    ty: TransactionType::Dispute,
+                    client: 1,
+                    tx,
+                    amount: None,
+                    state: DisputeState::Normal,
+                },
+                &Error::ReferenceDoesNotExist,
+            )
+        };
+
+        // Flushed rejections are in the file before it is closed, and a later writer appending to
+        // the same file writes no second header
+        let mut writer = CsvRejectionWriter::append(&path).await.unwrap();
+        writer.append_rejection(rejection(1)).await.unwrap();
+        writer.flush().await.unwrap();
+        let expected = "type,client,tx,amount,error,reason\n\
+            dispute,1,1,,ReferenceDoesNotExist,the referenced transaction does not exist\n";
+        assert_eq!(expected, tokio::fs::read_to_string(&path).await.unwrap());
+        writer.close().await.unwrap();
+
+        let mut writer = CsvRejectionWriter::append(&path).await.unwrap();
+        writer.append_rejection(rejection(2)).await.unwrap();
+        writer.close().await.unwrap();
+        let expected = format!(
+            "{}dispute,1,2,,ReferenceDoesNotExist,the referenced transaction does not exist\n",
+            expected
+        );
+        assert_eq!(expected, tokio::fs::read_to_string(&path).await.unwrap());
+    }
 }
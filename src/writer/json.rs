@@ -0,0 +1,234 @@
+use async_trait::async_trait;
+use std::path::Path;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+use super::*;
+
+/// Writes [`HumanReadableClient`]s as JSON to stdout, to a file, or to any other [`AsyncWrite`]
+/// implementor, either as a single array or as JSON-lines. Amounts are written as decimal strings
+/// such that they are never read back as floats
+pub struct JsonWriter {
+    writer: BufWriter<Output>,
+
+    /// Whether the clients are written as a JSON array rather than as JSON-lines
+    array: bool,
+
+    /// The number of clients written so far
+    written: usize,
+}
+
+impl JsonWriter {
+    pub fn new(array: bool) -> JsonWriter {
+        Self::from_writer(tokio::io::stdout(), array)
+    }
+
+    pub fn from_writer(
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+        array: bool,
+    ) -> JsonWriter {
+        Self::from_output(Output::from_writer(writer), array)
+    }
+
+    /// Write to the file at the given path, which is replaced all at once when the writer is closed
+    pub async fn create(path: impl AsRef<Path>, array: bool) -> std::io::Result<JsonWriter> {
+        Ok(Self::from_output(Output::create(path).await?, array))
+    }
+
+    fn from_output(output: Output, array: bool) -> JsonWriter {
+        JsonWriter {
+            writer: BufWriter::new(output),
+            array,
+            written: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl ClientWriter for JsonWriter {
+    async fn append_client(&mut self, client: Client) -> Result<(), Error> {
+        let client: HumanReadableClient = client.into();
+
+        // Every element of an array is written on its own line, separated by a comma from the last
+        let separator: &[u8] = match (self.array, self.written) {
+            (true, 0) => b"[\n",
+            (true, _) => b",\n",
+            (false, _) => b"",
+        };
+        self.writer.write_all(separator).await?;
+
+        let mut line = serde_json::to_vec(&client)?;
+        if !self.array {
+            line.push(b'\n');
+        }
+        self.writer.write_all(&line).await?;
+
+        self.written += 1;
+        Ok(())
+    }
+
+    async fn close(mut self) -> Result<(), Error> {
+        if self.array {
+            let end: &[u8] = if self.written == 0 { b"[]\n" } else { b"\n]\n" };
+            self.writer.write_all(end).await?;
+        }
+        commit(self.writer).await
+    }
+}
+
+/// Writes [`Rejection`]s to a file or to any other [`AsyncWrite`] implementor as JSON-lines, one
+/// JSON object per line
+pub struct JsonLinesRejectionWriter {
+    writer: BufWriter<Output>,
+}
+
+impl JsonLinesRejectionWriter {
+    /// Write to the file at the given path, which is replaced all at once when the writer is closed
+    pub async fn new(path: impl AsRef<Path>) -> std::io::Result<JsonLinesRejectionWriter> {
+        Ok(JsonLinesRejectionWriter {
+            writer: BufWriter::new(Output::create(path).await?),
+        })
+    }
+
+    /// Append to the file at the given path, see [`Output::append`]
+    pub async fn append(path: impl AsRef<Path>) -> std::io::Result<JsonLinesRejectionWriter> {
+        let (output, _) = Output::append(path).await?;
+        Ok(JsonLinesRejectionWriter {
+            writer: BufWriter::new(output),
+        })
+    }
+}
+
+/// Flush everything buffered and commit the [`Output`] it was written to
+async fn commit(mut writer: BufWriter<Output>) -> Result<(), Error> {
+    writer.flush().await?;
+    writer.into_inner().commit().await?;
+    Ok(())
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Writer(format!("{}", e))
+    }
+}
+
+#[async_trait]
+impl RejectionWriter for JsonLinesRejectionWriter {
+    async fn append_rejection(&mut self, rejection: Rejection) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&rejection)?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn close(self) -> Result<(), Error> {
+        commit(self.writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    use crate::{Client, DisputeState, Transaction, TransactionType};
+
+    fn clients() -> [Client; 2] {
+        [
+            Client {
+                client: 1,
+                available: 15000,
+                held: 0,
+                total: 15000,
+                locked: false,
+            },
+            Client {
+                client: 2,
+                available: -5,
+                held: 20000,
+                total: 19995,
+                locked: true,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn clients_as_array() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("clients.json");
+
+        let mut writer = JsonWriter::create(&path, true).await.unwrap();
+        for client in clients() {
+            writer.append_client(client).await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let expected = "[\n\
+            {\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false},\n\
+            {\"client\":2,\"available\":\"-0.0005\",\"held\":\"2.0000\",\"total\":\"1.9995\",\"locked\":true}\n\
+            ]\n";
+        let actual = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(expected, actual);
+
+        // An empty array is still valid JSON
+        JsonWriter::create(&path, true)
+            .await
+            .unwrap()
+            .close()
+            .await
+            .unwrap();
+        assert_eq!("[]\n", tokio::fs::read_to_string(&path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn clients_as_lines() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("clients.jsonl");
+
+        let mut writer = JsonWriter::create(&path, false).await.unwrap();
+        for client in clients() {
+            writer.append_client(client).await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let expected = "\
+            {\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+            {\"client\":2,\"available\":\"-0.0005\",\"held\":\"2.0000\",\"total\":\"1.9995\",\"locked\":true}\n";
+        let actual = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[tokio::test]
+    async fn rejection_report() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("rejections.jsonl");
+
+        let mut writer = JsonLinesRejectionWriter::new(&path).await.unwrap();
+        writer
+            .append_rejection(Rejection::new(
+                Transaction {
+                    ty: TransactionType::Dispute,
+                    client: 1,
+                    tx: 7,
+                    amount: None,
+                    state: DisputeState::Normal,
+                },
+                &Error::ReferenceDoesNotExist,
+            ))
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        let expected = "{\"type\":\"dispute\",\"client\":1,\"tx\":7,\"amount\":null,\
+            \"error\":\"ReferenceDoesNotExist\",\
+            \"reason\":\"the referenced transaction does not exist\"}\n";
+        let actual = tokio::fs::read_to_string(&path).await.unwrap();
+
+        assert_eq!(expected, actual);
+    }
+}
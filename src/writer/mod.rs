@@ -1,14 +1,187 @@
 use async_trait::async_trait;
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWrite, AsyncWriteExt},
+};
 
 use super::*;
 
 pub mod csv;
+pub mod json;
+
+/// The destination of a writer: stdout, a file, or any other [`AsyncWrite`] implementor. A file is
+/// written under a temporary name next to its path and only renamed to its path once
+/// [`Output::commit`] is called, such that the path never holds partial output. The temporary file
+/// is removed if the `Output` is dropped without being committed
+pub struct Output {
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    file: Option<PendingFile>,
+}
+
+/// The temporary file of an [`Output`] and the path it is moved to once committed
+struct PendingFile {
+    temporary: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Output {
+    pub fn from_writer(writer: impl AsyncWrite + Unpin + Send + 'static) -> Output {
+        Output {
+            writer: Box::new(writer),
+            file: None,
+        }
+    }
+
+    /// Write to the file at the given path, which is replaced all at once when committed
+    pub async fn create(path: impl AsRef<Path>) -> std::io::Result<Output> {
+        let path = path.as_ref().to_path_buf();
+        let name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("`{}` is not a file path", path.display()),
+            )
+        })?;
+
+        // The process ID keeps concurrent runs writing to the same path from clobbering each other
+        let mut temporary_name = std::ffi::OsString::from(".");
+        temporary_name.push(name);
+        temporary_name.push(format!(".{}.tmp", std::process::id()));
+        let temporary = path.with_file_name(temporary_name);
+
+        let file = File::create(&temporary).await?;
+        Ok(Output {
+            file: Some(PendingFile {
+                temporary,
+                path,
+                committed: false,
+            }),
+            ..Self::from_writer(file)
+        })
+    }
+
+    /// Append to the file at the given path, creating it if there is none. Unlike with
+    /// [`Output::create`] whatever is flushed is kept in the file, even if the `Output` is never
+    /// committed. Returns whether the file was empty, such that a header is only written once
+    pub async fn append(path: impl AsRef<Path>) -> std::io::Result<(Output, bool)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let empty = file.metadata().await?.len() == 0;
+        Ok((Self::from_writer(file), empty))
+    }
+
+    /// Flush everything written and move a file to its path
+    pub async fn commit(mut self) -> std::io::Result<()> {
+        self.writer.flush().await?;
+        if let Some(mut file) = self.file.take() {
+            File::open(&file.temporary).await?.sync_all().await?;
+            tokio::fs::rename(&file.temporary, &file.path).await?;
+            file.committed = true;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for Output {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temporary);
+        }
+    }
+}
 
 #[async_trait]
 pub trait ClientWriter {
     /// Append a [`Client`] to whatever output method the implementor uses.
     async fn append_client(&mut self, client: Client) -> Result<(), Error>;
 
-    /// Close the `ClientWriter`, flushing any data
+    /// Close the `ClientWriter`, flushing any data. Nothing is guaranteed to have been written
+    /// until the writer is closed
+    async fn close(self) -> Result<(), Error>;
+}
+
+/// Implementors of this trait record every [`Rejection`] produced while processing transactions
+/// such that it can be explained later why a transaction did not go through
+#[async_trait]
+pub trait RejectionWriter {
+    /// Append a [`Rejection`] to whatever output method the implementor uses.
+    async fn append_rejection(&mut self, rejection: Rejection) -> Result<(), Error>;
+
+    /// Flush every [`Rejection`] appended so far to the output, which for a file opened to be
+    /// appended to writes them to the file
+    async fn flush(&mut self) -> Result<(), Error>;
+
+    /// Close the `RejectionWriter`, flushing any data
     async fn close(self) -> Result<(), Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn client_output_replaced_on_close() {
+        let dir = TempDir::new_in("./").unwrap();
+        let path = dir.path().join("clients.csv");
+        tokio::fs::write(&path, "previous output\n").await.unwrap();
+
+        let client = Client {
+            client: 1,
+            available: 10000,
+            held: 0,
+            total: 10000,
+            locked: false,
+        };
+
+        // A writer which is never closed leaves the previous output and no temporary file behind
+        let mut writer = csv::CsvWriter::create(&path).await.unwrap();
+        writer.append_client(client).await.unwrap();
+        drop(writer);
+        assert_eq!(
+            "previous output\n",
+            tokio::fs::read_to_string(&path).await.unwrap()
+        );
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+
+        let mut writer = csv::CsvWriter::create(&path).await.unwrap();
+        writer.append_client(client).await.unwrap();
+        assert_eq!(
+            "previous output\n",
+            tokio::fs::read_to_string(&path).await.unwrap()
+        );
+        writer.close().await.unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n",
+            tokio::fs::read_to_string(&path).await.unwrap()
+        );
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+}